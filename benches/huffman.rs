@@ -0,0 +1,93 @@
+//! Compare the table-driven Huffman decoder against a simple reference
+//! decoder (which searches for the length of each codeword, and looks up
+//! symbols in a hashmap). The "builtin" case goes through `decompress_huff`
+//! with the cached CSME11 dictionaries.
+
+use std::collections::HashMap;
+use criterion::{ black_box, criterion_group, criterion_main, Criterion };
+use csme_rs::{ ext::*, huffman::* };
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+const CHUNK_LEN: usize = 0x1000;
+
+/// Reference decoder, using the same approach as the original
+/// implementation.
+struct NaiveDecoder {
+    /// (smallest codeword aligned to the top of a `u32`, length)
+    shape: Vec<(u32, u32)>,
+    symbols: HashMap<(u32, u32), Vec<u8>>,
+}
+impl NaiveDecoder {
+    fn new(dict: &HuffmanDictionary) -> Self {
+        let mut shape: Vec<(u32, u32)> = Vec::new();
+        let mut symbols = HashMap::new();
+        for (bitlen, codeword, symbol) in dict.codewords() {
+            let value = codeword << (32 - bitlen);
+            match shape.iter_mut().find(|(_, len)| *len == bitlen) {
+                Some(entry) => entry.0 = entry.0.min(value),
+                None => shape.push((value, bitlen)),
+            }
+            symbols.insert((bitlen, codeword), symbol.to_vec());
+        }
+        shape.sort_by_key(|(_, len)| *len);
+        Self { shape, symbols }
+    }
+
+    fn decompress(&self, src: &[u8], len: usize) -> Vec<u8> {
+        let num_chunks = len / CHUNK_LEN;
+        let data = &src[num_chunks * 4..];
+        let mut res = Vec::new();
+        for idx in 0..num_chunks {
+            let ent = u32::from_le_bytes([
+                src[idx * 4], src[idx * 4 + 1], src[idx * 4 + 2], src[idx * 4 + 3]
+            ]);
+            let mut cur = (ent & 0x01ff_ffff) as usize;
+            let tail = (idx + 1) * CHUNK_LEN;
+            let mut bit_buffer: u32 = 0;
+            let mut bit_avail: u32 = 0;
+            while res.len() < tail {
+                while bit_avail <= 24 && cur < data.len() {
+                    bit_buffer |= (data[cur] as u32) << (24 - bit_avail);
+                    cur += 1;
+                    bit_avail += 8;
+                }
+                let bitlen = self.shape.iter()
+                    .find(|(min, _)| bit_buffer >= *min)
+                    .map(|(_, len)| *len).unwrap();
+                let codeword = bit_buffer >> (32 - bitlen);
+                bit_buffer <<= bitlen;
+                bit_avail -= bitlen;
+                res.extend_from_slice(&self.symbols[&(bitlen, codeword)]);
+            }
+        }
+        res
+    }
+}
+
+fn bench_decompress(c: &mut Criterion) {
+    let data = common::module_data(0x40000, 1);
+    let compressed = compress_huff(&data).unwrap();
+    let attrs = common::module_attrs(CompressionType::Huff, data.len(),
+        compressed.len(), [0; 32]);
+
+    let dicts = HuffmanDictionaries::csme11();
+    let naive = NaiveDecoder::new(&dicts.code);
+    assert_eq!(naive.decompress(&compressed, data.len()), data);
+
+    let mut group = c.benchmark_group("decompress_huff");
+    group.bench_function("table", |b| b.iter(|| {
+        decompress_huff_with(black_box(&compressed), &attrs, dicts).unwrap()
+    }));
+    group.bench_function("builtin", |b| b.iter(|| {
+        decompress_huff(black_box(&compressed), &attrs).unwrap()
+    }));
+    group.bench_function("naive", |b| b.iter(|| {
+        naive.decompress(black_box(&compressed), data.len())
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_decompress);
+criterion_main!(benches);
@@ -1,7 +1,16 @@
 
 use std::env;
-use std::str::from_utf8;
-use csme_rs::{ FromBytes, fpt::*, part::*, ext::* };
+use csme_rs::{ image::*, fpt::*, part::*, ext::*, keys::*, bootguard::* };
+
+/// Print the IDs of any extensions we didn't know how to parse.
+fn report_unknown(name: &str, extensions: &[ManifestExtension]) {
+    for ext in extensions {
+        if let ExtensionData::Unknown { id, raw } = &ext.data {
+            println!("[!] Unknown extension {:08x} ({:#x} bytes) in {}",
+                id, raw.len(), name);
+        }
+    }
+}
 
 /// Read bytes from a file.
 fn read_file(filename: &str) -> Vec<u8> {
@@ -11,70 +20,191 @@ fn read_file(filename: &str) -> Vec<u8> {
     let mut file = File::open(filename).expect("Couldn't open file");
     let file_len = std::fs::metadata(filename).unwrap().len() as usize;
     let mut res = vec![0; file_len];
-    file.read(&mut res).unwrap();
+    file.read_exact(&mut res).unwrap();
     res
 }
 
-pub fn main() -> Result<(), &'static str> {
-    use hex::encode;
-    use sha2::{ Sha256, Digest };
+/// Print the contents of some code partition.
+fn dump_partition(name: &str, fpt_name: Option<[u8; 4]>, data: &[u8],
+    keys: &KeyDatabase) -> Result<(), Box<dyn std::error::Error>>
+{
+    let part = CodePartition::new(data)?;
+
+    let hdr = &part.man.header;
+    let date = hdr.date().date().map(|d| d.to_string())
+        .unwrap_or_else(|| format!("{:08x}", hdr.date().0));
+    println!("[*] Manifest for {}: version {}, SVN {}, built {}{}", name,
+        hdr.firmware_version(), hdr.secure_version_number(), date,
+        if hdr.flags().debug_signed() { " (debug)" } else { "" });
+
+    println!("[*] Manifest signature for {}: {:?}", name,
+        part.man.verify_signature());
+    let key_hash = part.man.crypto.public_key_hash();
+    println!("[*] Public key hash: {} ({:?})", hex::encode(key_hash),
+        keys.classify(&key_hash));
+
+    match part.verify_partition_info(fpt_name) {
+        Ok(issues) if issues.is_empty() =>
+            println!("[*] Partition info for {} matches", name),
+        Ok(issues) => for issue in issues {
+            println!("[!] Partition info mismatch: {:x?}", issue);
+        },
+        Err(e) => println!("[!] Couldn't check partition info: {}", e),
+    }
+
+    for issue in part.verify_metadata() {
+        println!("[!] Module metadata mismatch: {:x?}", issue);
+    }
+
+    println!("[*] Directory for {}", name);
+    if !part.cpd.verify_checksum() {
+        println!("[!] Bad directory checksum {:x?} (expected {:x?})",
+            part.cpd.checksum(), part.cpd.compute_checksum());
+    }
+    for f in part.cpd.entries.iter() {
+        println!("  - {}", f.filename());
+    }
+
+    report_unknown(name, &part.man.extensions);
+    for (name, m) in part.modules.iter() {
+        println!("  => Found module '{}'", name);
+        report_unknown(name, &m.ext);
+        println!("     | Compression:     {:?}", m.attr.compression_type()?);
+        println!("     | Module ID:       {:04x}", m.attr.ven_module_id);
+        match m.verify_digest()? {
+            DigestStatus::Valid => println!("     | Digest:          OK"),
+            DigestStatus::Invalid { expected, computed } => {
+                println!("     | Digest:          mismatch");
+                println!("     |   expected {}", hex::encode(expected));
+                println!("     |   computed {}", hex::encode(computed));
+            },
+            DigestStatus::Unsupported => {
+                println!("     | Digest:          unknown byte order");
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Print any Boot Guard key manifests and boot policy manifests in `data`.
+///
+/// NOTE: The markers can also show up elsewhere in the image (e.g. in the
+/// code that parses the manifests), so every match is tried and the first
+/// one that parses is used. Parsing the FIT would avoid this.
+fn dump_boot_guard(data: &[u8], keys: &KeyDatabase) {
+    let find_all = |marker: &'static [u8]| data.windows(marker.len())
+        .enumerate()
+        .filter(move |(_, w)| *w == marker)
+        .map(|(off, _)| off);
+
+    let mut km = None;
+    for off in find_all(b"__KEYM__") {
+        match KeyManifest::new(&data[off..]) {
+            Ok(x) => {
+                km = Some((off, x));
+                break;
+            },
+            Err(e) =>
+                println!("[!] Couldn't parse key manifest at {:#x}: {}",
+                    off, e),
+        }
+    }
+    if let Some((off, km)) = km.as_ref() {
+        let key_hash = km.signature.public_key_hash();
+        println!("[*] Key manifest at {:#x}: SVN {}, signature {:?}",
+            off, km.header.km_svn, km.verify_signature());
+        println!("[*] Key manifest public key hash: {} ({:?})",
+            hex::encode(key_hash), keys.classify(&key_hash));
+        for key in km.keys.iter() {
+            println!("  - {:?} {} {:?}", key.algorithm,
+                hex::encode(&key.digest), key.usage);
+        }
+    }
 
+    for off in find_all(b"__ACBP__") {
+        match BootPolicyManifest::new(&data[off..]) {
+            Ok(bpm) => {
+                println!("[*] Boot policy manifest at {:#x}: SVN {}, \
+                    signature {:?}", off, bpm.header.bpm_svn,
+                    bpm.verify_signature());
+                if let Some((_, km)) = km.as_ref() {
+                    println!("[*] Boot policy key trusted by key manifest: \
+                        {}", bpm.is_trusted_by(km));
+                }
+                break;
+            },
+            Err(e) =>
+                println!("[!] Couldn't parse boot policy manifest at {:#x}: \
+                    {}", off, e),
+        }
+    }
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        println!("usage: {} <CSME image>", args[0]);
-        return Err("Invalid arguments");
+        println!("usage: {} <CSME image> [key list]", args[0]);
+        return Err("Invalid arguments".into());
     }
 
     let file_buf = read_file(&args[1]);
 
-    let fpt = FlashPartitionTable::from_bytes(&file_buf[0x10..0x1000]);
+    let mut keys = KeyDatabase::new();
+    if let Some(filename) = args.get(2) {
+        keys.load(&std::fs::read_to_string(filename)?)?;
+    }
+
+    let image = MeImage::new(&file_buf)?;
+    println!("[*] Found {:?} image, ME region at {:#x}",
+        image.kind, image.me_offset);
+    if image.kind == ImageKind::SpiFlash {
+        dump_boot_guard(&file_buf, &keys);
+    }
+
+    let fpt = match image.fpt() {
+        Some(fpt) => fpt?,
+        None => {
+            // Update images are a single code partition
+            if let Err(e) = dump_partition("update image", None,
+                image.me_region, &keys) {
+                println!("[!] Couldn't parse update image: {}", e);
+            }
+            return Ok(());
+        },
+    };
+
+    println!("[*] FPT version {:?}, built with FITC {}",
+        fpt.header.version(), fpt.header.fitc_version());
+    if !fpt.verify_checksum() {
+        println!("[!] Bad FPT checksum {:x?} (expected {:x?})",
+            fpt.checksum(), fpt.compute_checksum());
+    }
 
     for entry in fpt.entries.iter() {
-        if !entry.attrs.entry_valid() { 
+        if !entry.attrs.entry_valid() {
             continue
         }
+        let name = String::from_utf8_lossy(&entry.name);
         if entry.attrs.kind() != PartitionType::Code {
-            println!("[!] Skipped data partition {}", 
-                from_utf8(&entry.name).unwrap());
+            println!("[!] Skipped {:?} partition {}", entry.attrs.kind(), name);
             continue;
         }
 
-        println!("[*] Parsing partition {}", from_utf8(&entry.name).unwrap());
+        println!("[*] Parsing partition {}", name);
         let off = entry.offset as usize;
         let len = entry.length as usize;
-        assert!(off + len <= file_buf.len());
-        assert!(off < file_buf.len());
-        let part = CodePartition::new(&file_buf[off..off+len]);
-
-        println!("[*] Directory for {}", from_utf8(&entry.name).unwrap());
-        for f in part.cpd.entries.iter() {
-            println!("  - {}", f.filename());
+        let data = match image.me_region.get(off..off + len) {
+            Some(data) => data,
+            None => {
+                println!("[!] Partition {} is outside of the ME region", name);
+                continue;
+            },
+        };
+        if let Err(e) = dump_partition(&name, Some(entry.name), data, &keys) {
+            println!("[!] Couldn't parse partition {} at {:#x}: {}",
+                name, off, e);
         }
-
-        for (name, m) in part.modules.iter() {
-
-            // Compute the SHA256 digest for this module.
-            // NOTE: I think I'm doing something wrong here
-            let computed_digest = {
-                let mut hasher = Sha256::new();
-                match m.attr.compression_type() {
-                    CompressionType::None => unimplemented!(),
-                    CompressionType::Lzma => hasher.update(&m.raw_data),
-                    CompressionType::Huff => hasher.update(&m.raw_data),
-                }
-                hasher.finalize()
-            };
-
-            println!("  => Found module '{}'", name);
-            println!("     | Compression:     {:?}", m.attr.compression_type());
-            //println!("     | Module ID:       {:04x}", m.attr.ven_module_id);
-            //println!("     | {:02x?}", m.attr.sha256_digest);
-            //println!("     | {:02x?}", computed_digest);
-        }
-
     }
 
     Ok(())
 }
-
-
@@ -0,0 +1,341 @@
+//! Boot Guard key manifests (`__KEYM__`) and boot policy manifests
+//! (`__ACBP__`).
+//!
+//! These are signed separately from the partition manifests. The hash of
+//! the key used to sign the key manifest is fused into the platform by the
+//! OEM, and the key manifest lists the hashes of the keys trusted to sign
+//! other structures (i.e. the boot policy manifest).
+//!
+//! NOTE: Only version 2.x of these structures (Boot Guard 2.0) is
+//! supported.
+
+use std::fmt;
+use crate::{ Error, rsa, bytes::Field, FromBytes };
+use sha2::{ Sha256, Digest };
+
+/// Hash algorithm identifiers (from the TPM 2.0 `TPM_ALG_ID` values).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sm3,
+    Unknown(u16),
+}
+impl From<u16> for HashAlgorithm {
+    fn from(x: u16) -> Self {
+        match x {
+            0x04 => Self::Sha1, 0x0b => Self::Sha256, 0x0c => Self::Sha384,
+            0x12 => Self::Sm3,
+            _ => Self::Unknown(x),
+        }
+    }
+}
+
+/// Bits describing what some key can be used to sign.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct KeyUsage(pub u64);
+impl KeyUsage {
+    /// The key can sign a boot policy manifest.
+    pub fn boot_policy(&self) -> bool { self.0 & (1 << 0) != 0 }
+    /// The key can sign an FIT patch manifest.
+    pub fn fit_patch(&self) -> bool { self.0 & (1 << 1) != 0 }
+    /// The key can sign an ACM manifest.
+    pub fn acm(&self) -> bool { self.0 & (1 << 2) != 0 }
+    /// The key can sign an SDEV structure.
+    pub fn sdev(&self) -> bool { self.0 & (1 << 3) != 0 }
+}
+impl fmt::Debug for KeyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let names = [
+            (self.boot_policy(), "BootPolicy"), (self.fit_patch(), "FitPatch"),
+            (self.acm(), "Acm"), (self.sdev(), "Sdev"),
+        ];
+        write!(f, "KeyUsage({:#x}", self.0)?;
+        for (_, name) in names.iter().filter(|(set, _)| *set) {
+            write!(f, " {}", name)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Hash of some key listed in a key manifest.
+#[derive(Clone, Debug)]
+pub struct KeyHash {
+    pub usage: KeyUsage,
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+/// Public key and signature (`KEY_AND_SIGNATURE_STRUCT`) at the end of a
+/// key manifest or boot policy manifest.
+///
+/// NOTE: Like in the partition manifests, the modulus and signature are
+/// stored in little-endian order.
+#[derive(Clone, Debug)]
+pub struct KeySignature {
+    pub version: u8,
+    pub key_algorithm: u16,
+    pub exponent: u32,
+    pub modulus: Vec<u8>,
+    pub signature_scheme: u16,
+    pub hash_algorithm: HashAlgorithm,
+    pub signature: Vec<u8>,
+}
+impl KeySignature {
+    /// `TPM_ALG_RSA`
+    const ALG_RSA: u16 = 0x01;
+    /// `TPM_ALG_RSASSA` (PKCS#1 v1.5)
+    const SCHEME_RSASSA: u16 = 0x14;
+
+    pub fn new(x: &[u8]) -> crate::Result<Self> {
+        let name = "KeySignature";
+        let hdr = crate::take(x, 0, 10, name)?;
+        let version = hdr[0];
+        let key_algorithm = u16::read(&hdr[1..]);
+        if key_algorithm != Self::ALG_RSA {
+            return Err(Error::Invalid {
+                name, offset: 1, reason: "unsupported key algorithm",
+            });
+        }
+        let key_len = u16::read(&hdr[4..]) as usize / 8;
+        let exponent = u32::read(&hdr[6..]);
+        let modulus = crate::take(x, 10, key_len, name)?.to_vec();
+
+        let off = 10 + key_len;
+        let sig_hdr = crate::take(x, off, 7, name)?;
+        let signature_scheme = u16::read(sig_hdr);
+        let sig_len = u16::read(&sig_hdr[3..]) as usize / 8;
+        let hash_algorithm = HashAlgorithm::from(u16::read(&sig_hdr[5..]));
+        let signature = crate::take(x, off + 7, sig_len, name)?.to_vec();
+
+        Ok(Self {
+            version, key_algorithm, exponent, modulus, signature_scheme,
+            hash_algorithm, signature,
+        })
+    }
+
+    /// Return the SHA-256 hash of the public key.
+    ///
+    /// NOTE: For Boot Guard, this only covers the modulus (as it is stored),
+    /// unlike [crate::man::CryptoBlock::public_key_hash].
+    pub fn public_key_hash(&self) -> [u8; 32] {
+        Sha256::digest(&self.modulus).into()
+    }
+
+    /// Check the signature over `data`.
+    pub fn verify(&self, data: &[u8]) -> rsa::SignatureStatus {
+        if self.signature_scheme != Self::SCHEME_RSASSA
+            || self.hash_algorithm != HashAlgorithm::Sha256
+        {
+            return rsa::SignatureStatus::UnsupportedScheme;
+        }
+        rsa::verify_pkcs1_sha256(&self.modulus, self.exponent,
+            &self.signature, data)
+    }
+}
+
+le_struct! {
+/// Key manifest header.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyManifestHeader {
+    pub marker: [u8; 8],
+    pub struct_version: u8,
+    pub reserved0: [u8; 3],
+    /// Offset of the [KeySignature] in the key manifest.
+    pub key_signature_offset: u16,
+    pub reserved1: [u8; 3],
+    pub km_version: u8,
+    pub km_svn: u8,
+    pub km_id: u8,
+    pub pub_key_hash_alg: u16,
+    pub key_count: u16,
+}
+}
+impl KeyManifestHeader {
+    const MARKER_KEYM: [u8; 8] = *b"__KEYM__";
+}
+impl crate::FromBytes for KeyManifestHeader {
+    fn validate(&self) -> crate::Result<()> {
+        if self.marker != Self::MARKER_KEYM {
+            return Err(Error::BadMagic { name: "KeyManifestHeader", offset: 0 });
+        }
+        if self.struct_version & 0xf0 != 0x20 {
+            return Err(Error::Invalid {
+                name: "KeyManifestHeader", offset: 8,
+                reason: "unsupported structure version",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A Boot Guard key manifest.
+pub struct KeyManifest {
+    pub header: KeyManifestHeader,
+    /// Hashes of the keys trusted by this manifest.
+    pub keys: Vec<KeyHash>,
+    pub signature: KeySignature,
+    /// Copy of the signed part of the manifest.
+    signed_data: Vec<u8>,
+}
+impl KeyManifest {
+    pub fn new(x: &[u8]) -> crate::Result<Self> {
+        let header = KeyManifestHeader::from_bytes(x)?;
+
+        let mut keys = Vec::new();
+        let mut off = KeyManifestHeader::SIZE;
+        for _ in 0..header.key_count {
+            let hdr = crate::take(x, off, 12, "KeyHash")?;
+            let usage = KeyUsage(u64::read(hdr));
+            let algorithm = HashAlgorithm::from(u16::read(&hdr[8..]));
+            let len = u16::read(&hdr[10..]) as usize;
+            let digest = crate::take(x, off + 12, len, "KeyHash")?.to_vec();
+            keys.push(KeyHash { usage, algorithm, digest });
+            off += 12 + len;
+        }
+
+        let sig_off = header.key_signature_offset as usize;
+        if sig_off < off {
+            return Err(Error::Invalid {
+                name: "KeyManifestHeader", offset: 10,
+                reason: "key signature overlaps the key hashes",
+            });
+        }
+        let signature = KeySignature::new(x.get(sig_off..).unwrap_or(&[]))
+            .map_err(|e| e.shift(sig_off))?;
+        let signed_data = x[..sig_off].to_vec();
+        Ok(Self { header, keys, signature, signed_data })
+    }
+
+    /// Check the signature on this manifest.
+    pub fn verify_signature(&self) -> rsa::SignatureStatus {
+        self.signature.verify(&self.signed_data)
+    }
+
+    /// Return the keys which can be used to sign a boot policy manifest.
+    pub fn boot_policy_keys(&self) -> impl Iterator<Item=&KeyHash> {
+        self.keys.iter().filter(|k| k.usage.boot_policy())
+    }
+}
+
+le_struct! {
+/// Boot policy manifest header.
+#[derive(Clone, Copy, Debug)]
+pub struct BootPolicyHeader {
+    pub marker: [u8; 8],
+    pub struct_version: u8,
+    pub header_struct_version: u8,
+    pub header_size: u16,
+    /// Offset of the [KeySignature] in the boot policy manifest.
+    pub key_signature_offset: u16,
+    pub bpm_revision: u8,
+    pub bpm_svn: u8,
+    pub acm_svn: u8,
+    pub reserved: u8,
+    pub nem_pages: u16,
+}
+}
+impl BootPolicyHeader {
+    const MARKER_ACBP: [u8; 8] = *b"__ACBP__";
+}
+impl crate::FromBytes for BootPolicyHeader {
+    fn validate(&self) -> crate::Result<()> {
+        if self.marker != Self::MARKER_ACBP {
+            return Err(Error::BadMagic { name: "BootPolicyHeader", offset: 0 });
+        }
+        if self.struct_version & 0xf0 != 0x20 {
+            return Err(Error::Invalid {
+                name: "BootPolicyHeader", offset: 8,
+                reason: "unsupported structure version",
+            });
+        }
+        if (self.header_size as usize) < Self::SIZE {
+            return Err(Error::Invalid {
+                name: "BootPolicyHeader", offset: 10,
+                reason: "header size is smaller than the header",
+            });
+        }
+        Ok(())
+    }
+}
+
+le_struct! {
+/// Header for an element in a boot policy manifest.
+#[derive(Clone, Copy, Debug)]
+pub struct BootPolicyElementHeader {
+    /// Marker for this element (i.e. `__IBBS__`)
+    pub marker: [u8; 8],
+    pub struct_version: u8,
+    pub reserved: u8,
+    pub element_size: u16,
+}
+}
+impl crate::FromBytes for BootPolicyElementHeader {}
+
+/// An element in a boot policy manifest.
+#[derive(Clone, Debug)]
+pub struct BootPolicyElement {
+    pub hdr: BootPolicyElementHeader,
+    /// Contents of the element (after the header).
+    pub data: Vec<u8>,
+}
+
+/// A Boot Guard boot policy manifest.
+pub struct BootPolicyManifest {
+    pub header: BootPolicyHeader,
+    /// Elements before the signature element (`__PMSG__`).
+    pub elements: Vec<BootPolicyElement>,
+    pub signature: KeySignature,
+    /// Copy of the signed part of the manifest.
+    signed_data: Vec<u8>,
+}
+impl BootPolicyManifest {
+    const MARKER_PMSG: [u8; 8] = *b"__PMSG__";
+
+    pub fn new(x: &[u8]) -> crate::Result<Self> {
+        let header = BootPolicyHeader::from_bytes(x)?;
+        let sig_off = header.key_signature_offset as usize;
+
+        // NOTE: The signature element doesn't have a size, so we stop when
+        // we find it.
+        let mut elements = Vec::new();
+        let mut off = header.header_size as usize;
+        while off < sig_off {
+            if x.get(off..off + 8) == Some(&Self::MARKER_PMSG) {
+                break;
+            }
+            let hdr: BootPolicyElementHeader = crate::read_at(x, off)?;
+            let len = hdr.element_size as usize;
+            if len < BootPolicyElementHeader::SIZE {
+                return Err(Error::Invalid {
+                    name: "BootPolicyElementHeader", offset: off,
+                    reason: "element size is smaller than its header",
+                });
+            }
+            let data = crate::take(x, off + BootPolicyElementHeader::SIZE,
+                len - BootPolicyElementHeader::SIZE, "BootPolicyElement")?;
+            elements.push(BootPolicyElement { hdr, data: data.to_vec() });
+            off += len;
+        }
+
+        let signature = KeySignature::new(x.get(sig_off..).unwrap_or(&[]))
+            .map_err(|e| e.shift(sig_off))?;
+        let signed_data = x[..sig_off].to_vec();
+        Ok(Self { header, elements, signature, signed_data })
+    }
+
+    /// Check the signature on this manifest.
+    pub fn verify_signature(&self) -> rsa::SignatureStatus {
+        self.signature.verify(&self.signed_data)
+    }
+
+    /// Return whether or not the key used to sign this manifest is trusted
+    /// by some key manifest.
+    pub fn is_trusted_by(&self, km: &KeyManifest) -> bool {
+        let hash = self.signature.public_key_hash();
+        km.boot_policy_keys().any(|k| {
+            k.algorithm == HashAlgorithm::Sha256 && k.digest == hash
+        })
+    }
+}
@@ -0,0 +1,94 @@
+//! Little-endian encoding of on-disk structures.
+//!
+//! Structures are decoded and encoded field-by-field in declaration order,
+//! without any padding between fields, so this doesn't depend on the
+//! alignment of the input or on the endianness of the host.
+
+use std::convert::TryInto;
+
+/// Types with a fixed-size little-endian encoding.
+pub trait Field: Sized {
+    /// Length of the encoding (in bytes).
+    const SIZE: usize;
+
+    /// Decode a value from the first `SIZE` bytes of `x`.
+    ///
+    /// Panics if `x` is shorter than `SIZE`; callers are expected to check
+    /// the length of their input (see [crate::FromBytes::from_bytes]).
+    fn read(x: &[u8]) -> Self;
+
+    /// Append the encoding of this value to `out`.
+    fn write(&self, out: &mut Vec<u8>);
+}
+
+macro_rules! impl_field_int {
+    ($($ty:ty),*) => { $(
+        impl Field for $ty {
+            const SIZE: usize = std::mem::size_of::<$ty>();
+            fn read(x: &[u8]) -> Self {
+                <$ty>::from_le_bytes(x[..Self::SIZE].try_into().unwrap())
+            }
+            fn write(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+        impl crate::FromBytes for $ty {}
+    )* }
+}
+impl_field_int!(u8, u16, u32, u64);
+
+impl<const N: usize> Field for [u8; N] {
+    const SIZE: usize = N;
+    fn read(x: &[u8]) -> Self {
+        x[..N].try_into().unwrap()
+    }
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+}
+
+/// Declare a structure along with an implementation of [Field].
+///
+/// Also accepts a tuple struct wrapping a single [Field] (for bitfields).
+macro_rules! le_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $( $(#[$fmeta:meta])* $fvis:vis $field:ident : $ty:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name { $( $(#[$fmeta])* $fvis $field: $ty, )* }
+        impl $crate::bytes::Field for $name {
+            const SIZE: usize = 0 $( + <$ty as $crate::bytes::Field>::SIZE )*;
+            #[allow(unused_assignments)]
+            fn read(x: &[u8]) -> Self {
+                let mut off = 0;
+                $(
+                    let $field = <$ty as $crate::bytes::Field>::read(&x[off..]);
+                    off += <$ty as $crate::bytes::Field>::SIZE;
+                )*
+                Self { $( $field, )* }
+            }
+            fn write(&self, out: &mut Vec<u8>) {
+                $( <$ty as $crate::bytes::Field>::write(&self.$field, out); )*
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident($fvis:vis $ty:ty);
+    ) => {
+        $(#[$meta])*
+        $vis struct $name($fvis $ty);
+        impl $crate::bytes::Field for $name {
+            const SIZE: usize = <$ty as $crate::bytes::Field>::SIZE;
+            fn read(x: &[u8]) -> Self {
+                Self(<$ty as $crate::bytes::Field>::read(x))
+            }
+            fn write(&self, out: &mut Vec<u8>) {
+                <$ty as $crate::bytes::Field>::write(&self.0, out);
+            }
+        }
+    };
+}
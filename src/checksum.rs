@@ -0,0 +1,28 @@
+//! Checksums over flash partition tables and code partition directories.
+//!
+//! Older headers use an 8-bit checksum chosen such that the sum of all
+//! bytes is zero. Newer headers use a CRC32. In both cases, the checksum
+//! field itself is zero when computing the checksum.
+
+/// A checksum stored in (or computed for) some header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Sum8(u8),
+    Crc32(u32),
+}
+impl Checksum {
+    /// Compute an 8-bit checksum over `data`, where the checksum itself is
+    /// stored at `offset`.
+    pub(crate) fn sum8(mut data: Vec<u8>, offset: usize) -> Self {
+        data[offset] = 0;
+        Checksum::Sum8(data.iter()
+            .fold(0u8, |sum, x| sum.wrapping_add(*x)).wrapping_neg())
+    }
+
+    /// Compute a CRC32 over `data`, where the checksum itself is stored at
+    /// `offset`.
+    pub(crate) fn crc32(mut data: Vec<u8>, offset: usize) -> Self {
+        data[offset..offset + 4].fill(0);
+        Checksum::Crc32(crc32fast::hash(&data))
+    }
+}
@@ -1,32 +1,75 @@
-use std::convert::TryInto;
+
 use std::fmt;
-use std::ffi;
-use crate::{ FromBytes, man::* };
+use crate::{ FromBytes, ToBytes, Error, bytes::Field, checksum::Checksum };
 
 /// Directory of files contained in some code partition.
 pub struct CodePartitionDirectory {
     pub header: CpdHeader,
+    /// CRC32 following the header (only in version 2 headers).
+    pub crc32: Option<u32>,
     pub entries: Vec<CpdEntry>,
 }
 impl CodePartitionDirectory {
-    pub fn new(data: &[u8]) -> Self {
-        let header = CpdHeader::from_bytes(&data[0x00..0x10]);
+    pub fn new(data: &[u8]) -> crate::Result<Self> {
+        let header = CpdHeader::from_bytes(data)?;
+        let crc32 = match header.version() {
+            CpdHeaderVersion::V1 => None,
+            CpdHeaderVersion::V2 => Some(crate::read_at(data, CpdHeader::SIZE)?),
+        };
         let num_entries = header.entries as usize;
-        let mut entries = vec![CpdEntry::default(); num_entries];
-        unsafe {
-            entries.copy_from_slice(
-                std::slice::from_raw_parts(
-                    data[0x10..].as_ptr() as *const CpdEntry,
-                    num_entries
-                ),
-            );
+        let entries = crate::read_array_at(data, header.header_length as usize,
+            num_entries)?;
+        Ok(CodePartitionDirectory { header, crc32, entries })
+    }
+
+    /// Return the checksum stored in the header.
+    pub fn checksum(&self) -> Checksum {
+        match self.crc32 {
+            Some(crc32) => Checksum::Crc32(crc32),
+            None => Checksum::Sum8(self.header.checksum),
+        }
+    }
+
+    /// Compute the checksum over the header and entries.
+    ///
+    /// Version 1 headers use an 8-bit checksum. Version 2 headers use a
+    /// CRC32.
+    pub fn compute_checksum(&self) -> Checksum {
+        let data = self.to_bytes();
+        match self.header.version() {
+            CpdHeaderVersion::V1 => {
+                Checksum::sum8(data, CpdHeader::CHECKSUM_OFFSET)
+            },
+            CpdHeaderVersion::V2 => Checksum::crc32(data, CpdHeader::SIZE),
+        }
+    }
+
+    /// Return whether or not the stored checksum is correct.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum() == self.compute_checksum()
+    }
+
+    /// Recompute the checksum and store it in the header.
+    pub fn update_checksum(&mut self) {
+        match self.compute_checksum() {
+            Checksum::Sum8(x) => self.header.checksum = x,
+            Checksum::Crc32(x) => self.crc32 = Some(x),
+        }
+    }
+}
+
+impl crate::ToBytes for CodePartitionDirectory {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        self.header.write(out);
+        if let Some(crc32) = self.crc32 {
+            crc32.write(out);
         }
-        CodePartitionDirectory { header, entries }
+        self.entries.iter().for_each(|e| e.write(out));
     }
 }
 
+le_struct! {
 /// Header for a code partition directory.
-#[repr(C)]
 #[derive(Clone, Copy, Default)]
 pub struct CpdHeader {
     pub marker: [u8; 4],
@@ -34,33 +77,64 @@ pub struct CpdHeader {
     pub header_version: u8,
     pub entry_version: u8,
     pub header_length: u8,
+    /// 8-bit checksum (reserved in version 2 headers).
     pub checksum: u8,
     pub partition_name: [u8; 4],
 }
+}
 impl fmt::Debug for CpdHeader {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        let partition_name = std::str::from_utf8(&self.partition_name)
-            .expect("Couldn't interpret CPD header partition name as UTF-8")
-            .trim_end_matches(char::from(0));
+        let partition_name = String::from_utf8_lossy(&self.partition_name);
         f.debug_struct("CpdHeader")
-            .field("partition_name", &partition_name)
+            .field("partition_name",
+                &partition_name.trim_end_matches(char::from(0)))
+            .field("version", &self.version())
             .field("entries", &self.entries)
             .finish()
     }
 }
 impl CpdHeader {
     const MARKER_CPD: [u8; 4] = *b"$CPD";
+    const CHECKSUM_OFFSET: usize = 0x0b;
+
+    /// Return the version of this header.
+    ///
+    /// NOTE: This is only meaningful after the header has been validated.
+    pub fn version(&self) -> CpdHeaderVersion {
+        if self.header_version == 2 {
+            CpdHeaderVersion::V2
+        } else {
+            CpdHeaderVersion::V1
+        }
+    }
+}
+
+/// Different versions of the code partition directory header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpdHeaderVersion {
+    /// 0x10-byte header with an 8-bit checksum (CSME 11)
+    V1,
+    /// 0x14-byte header with a trailing CRC32 (CSME 12 and later)
+    V2,
 }
 impl crate::FromBytes for CpdHeader {
-    fn validate(&self) -> Result<(), &'static str> {
-        assert_eq!(self.marker, Self::MARKER_CPD);
-        assert_eq!(self.header_length, 0x10);
+    fn validate(&self) -> crate::Result<()> {
+        if self.marker != Self::MARKER_CPD {
+            return Err(Error::BadMagic { name: "CpdHeader", offset: 0 });
+        }
+        match (self.header_version, self.header_length) {
+            (1, 0x10) | (2, 0x14) => {},
+            _ => return Err(Error::Invalid {
+                name: "CpdHeader", offset: 0,
+                reason: "unexpected header version or length",
+            }),
+        }
         Ok(())
     }
 }
 
+le_struct! {
 /// An entry in some code partition directory.
-#[repr(C)]
 #[derive(Clone, Copy, Default)]
 pub struct CpdEntry {
     pub name: [u8; 12],
@@ -68,13 +142,25 @@ pub struct CpdEntry {
     pub length: u32,
     pub reserved: u32,
 }
+}
+impl crate::FromBytes for CpdEntry {
+    fn validate(&self) -> crate::Result<()> {
+        // Entry names are used as keys elsewhere, so reject them early.
+        // Anything after the first NUL is padding.
+        if std::str::from_utf8(self.name_bytes()).is_err() {
+            return Err(Error::Invalid {
+                name: "CpdEntry", offset: 0,
+                reason: "entry name is not UTF-8",
+            });
+        }
+        Ok(())
+    }
+}
 impl fmt::Debug for CpdEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        let name = std::str::from_utf8(&self.name)
-            .expect("Couldn't interpret CPD entry name as UTF-8")
-            .trim_end_matches(char::from(0));
+        let name = String::from_utf8_lossy(&self.name);
         f.debug_struct("CpdEntry")
-            .field("name", &name)
+            .field("name", &name.trim_end_matches(char::from(0)))
             .field("addr", &self.attrs.address())
             .field("length", &self.length)
             .field("compressed", &self.attrs.compress_flag())
@@ -83,19 +169,32 @@ impl fmt::Debug for CpdEntry {
 }
 impl CpdEntry {
     /// Return the filename (as a reference to a UTF-8 string).
-    pub fn filename(&self) -> &str { 
-        std::str::from_utf8(&self.name)
-            .expect("Couldn't interpret CPD entry name as UTF-8")
-            .trim_end_matches(char::from(0))
+    ///
+    /// If the name isn't valid UTF-8, this only returns the valid part.
+    pub fn filename(&self) -> &str {
+        let name = self.name_bytes();
+        match std::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(e) => std::str::from_utf8(&name[..e.valid_up_to()])
+                .unwrap_or_default(),
+        }
+    }
+    /// Return the bytes in the name before the first NUL.
+    fn name_bytes(&self) -> &[u8] {
+        let len = self.name.iter().position(|x| *x == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
     }
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize { self.length as usize }
     pub fn offset(&self) -> usize { self.attrs.address() as usize }
 }
 
+le_struct! {
 /// Bitfield in [CpdEntry].
-#[repr(transparent)]
 #[derive(Clone, Copy, Default, Debug)]
 pub struct CpdEntryBits(pub u32);
+}
 impl CpdEntryBits {
     pub fn address(&self) -> u32 { self.0 & 0x01ff_ffff }
     pub fn compress_flag(&self) -> bool { (self.0 & 0x0200_0000) != 0 }
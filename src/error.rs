@@ -0,0 +1,89 @@
+//! Errors produced while parsing an image.
+
+use std::fmt;
+
+/// Result type used by every parser in this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Describes why some input couldn't be parsed.
+///
+/// Every variant carries the name of the structure being parsed, and the
+/// offset of that structure (relative to the buffer that was passed to the
+/// public constructor which returned the error).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A structure doesn't begin with the expected marker.
+    BadMagic { name: &'static str, offset: usize },
+    /// The input ends before the end of a structure.
+    Truncated { name: &'static str, offset: usize, len: usize, avail: usize },
+    /// A module uses a compression type we don't know about.
+    UnknownCompression { name: &'static str, offset: usize, kind: u8 },
+    /// Compressed data couldn't be decompressed.
+    Decompression { name: &'static str, offset: usize, reason: String },
+    /// Some field in a structure has an unexpected value.
+    Invalid { name: &'static str, offset: usize, reason: &'static str },
+}
+impl Error {
+    /// Return the name of the structure associated with this error.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BadMagic { name, .. }
+            | Self::Truncated { name, .. }
+            | Self::UnknownCompression { name, .. }
+            | Self::Decompression { name, .. }
+            | Self::Invalid { name, .. } => name,
+        }
+    }
+
+    /// Return the offset of the structure associated with this error.
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::BadMagic { offset, .. }
+            | Self::Truncated { offset, .. }
+            | Self::UnknownCompression { offset, .. }
+            | Self::Decompression { offset, .. }
+            | Self::Invalid { offset, .. } => *offset,
+        }
+    }
+
+    /// Move the offset of this error by `base` bytes.
+    ///
+    /// Parsers use this when an error from a nested structure is passed up
+    /// to a caller which knows where that structure lives.
+    pub(crate) fn shift(mut self, base: usize) -> Self {
+        match &mut self {
+            Self::BadMagic { offset, .. }
+            | Self::Truncated { offset, .. }
+            | Self::UnknownCompression { offset, .. }
+            | Self::Decompression { offset, .. }
+            | Self::Invalid { offset, .. } => *offset += base,
+        }
+        self
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic { name, offset } =>
+                write!(f, "{} at {:#x}: bad marker", name, offset),
+            Self::Truncated { name, offset, len, avail } =>
+                write!(f, "{} at {:#x}: need {:#x} bytes, only {:#x} left",
+                    name, offset, len, avail),
+            Self::UnknownCompression { name, offset, kind } =>
+                write!(f, "{} at {:#x}: unknown compression type {}",
+                    name, offset, kind),
+            Self::Decompression { name, offset, reason } =>
+                write!(f, "{} at {:#x}: decompression failed: {}",
+                    name, offset, reason),
+            Self::Invalid { name, offset, reason } =>
+                write!(f, "{} at {:#x}: {}", name, offset, reason),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// Return the unqualified name of some type (for use in errors).
+pub(crate) fn type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
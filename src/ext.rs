@@ -1,6 +1,7 @@
 //! Metadata extensions.
 
-use crate::FromBytes;
+use std::convert::TryFrom;
+use crate::{ FromBytes, Error, bytes::Field };
 
 /// A container for different kinds of extensions.
 pub struct ManifestExtension {
@@ -8,17 +9,33 @@ pub struct ManifestExtension {
     pub data: ExtensionData,
 }
 impl ManifestExtension {
-    pub fn new(x: &[u8]) -> Self {
-        let hdr = ExtensionHeader::from_bytes(&x);
-        let ext_bytes = &x[std::mem::size_of::<ExtensionHeader>()..];
-        let data = ExtensionData::new(&hdr, ext_bytes);
-        ManifestExtension { hdr, data }
+    pub fn new(x: &[u8]) -> crate::Result<Self> {
+        let hdr = ExtensionHeader::from_bytes(x)?;
+        let hdr_len = ExtensionHeader::SIZE;
+        if (hdr.length as usize) < hdr_len {
+            return Err(Error::Invalid {
+                name: "ExtensionHeader", offset: 0,
+                reason: "extension length is smaller than its header",
+            });
+        }
+        let ext_bytes = crate::take(x, hdr_len, hdr.length as usize - hdr_len,
+            "ManifestExtension")?;
+        let data = ExtensionData::new(&hdr, ext_bytes)
+            .map_err(|e| e.shift(hdr_len))?;
+        Ok(ManifestExtension { hdr, data })
+    }
+}
+impl crate::ToBytes for ManifestExtension {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        self.hdr.write(out);
+        self.data.write_bytes(out);
     }
 }
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug)]
 pub struct ExtensionHeader { pub id: u32, pub length: u32 }
+}
 impl crate::FromBytes for ExtensionHeader {}
 
 /// Variable length extension data.
@@ -56,37 +73,58 @@ pub enum ExtensionData {
     ClientSystemInfo { data: ClientSystemInfoExt },
     /// Extension ID 0x0000_000d
     UserInfo { entries: Vec<UserInfoEntry> },
+    /// Extension ID 0x0000_000e (CSME 12 and later)
+    KeyManifest { data: KeyManifestExt, entries: Vec<KeyManifestEntry> },
+    /// Extension ID 0x0000_000f (CSME 12 and later)
+    SignedPackageInfo { data: SignedPackageInfoExt,
+        entries: Vec<ManifestModuleInfoExt>,
+    },
+    /// Extension ID 0x0000_0016 (CSME 12 and later)
+    IfwiPartitionInfo { data: IfwiPartitionInfoExt },
+    /// Some extension we don't know how to parse.
+    ///
+    /// This includes the other extensions used by CSME 12 and later (such
+    /// as IUNIT, ISH, and boot policy), and the SHA-384 variants of the
+    /// extensions above.
+    Unknown { id: u32, raw: Vec<u8> },
 }
 impl ExtensionData {
-    pub fn new(hdr: &ExtensionHeader, x: &[u8]) -> Self {
+    pub fn new(hdr: &ExtensionHeader, x: &[u8]) -> crate::Result<Self> {
         macro_rules! parse_ext_ent {
             ($enum:ident, $hdr:ident, $ent:ident) => {{
-                let data = $hdr::from_bytes(x);
-                let entry_off = std::mem::size_of::<$hdr>();
-                let entry_data = &x[entry_off..];
-                let num_entries = entry_data.len() / std::mem::size_of::<$ent>();
-                let mut entries = vec![$ent::default(); num_entries];
-                unsafe {
-                    entries.copy_from_slice(std::slice::from_raw_parts(
-                        entry_data.as_ptr() as *const $ent, num_entries));
-                }
+                let data = $hdr::from_bytes(x)?;
+                let entries = read_entries::<$ent>(&x[$hdr::SIZE..])
+                    .map_err(|e| e.shift($hdr::SIZE))?;
                 Self::$enum { data, entries }
             }}
         }
         macro_rules! parse_ent {
             ($enum:ident, $ent:ident) => {{
-                let entry_data = &x;
-                let num_entries = entry_data.len() / std::mem::size_of::<$ent>();
-                let mut entries = vec![$ent::default(); num_entries];
-                unsafe {
-                    entries.copy_from_slice(std::slice::from_raw_parts(
-                        entry_data.as_ptr() as *const $ent, num_entries));
-                }
+                let entries = read_entries::<$ent>(x)?;
                 Self::$enum { entries }
             }}
         }
+        macro_rules! parse_ext {
+            ($enum:ident, $hdr:ident) => {{
+                if x.len() != $hdr::SIZE {
+                    return Err(Error::Invalid {
+                        name: stringify!($hdr), offset: 0,
+                        reason: "unexpected extension length",
+                    });
+                }
+                Self::$enum { data: $hdr::from_bytes(x)? }
+            }}
+        }
 
-        match hdr.id {
+        // NOTE: Newer firmware (CSME 15 and later) uses SHA-384 in some
+        // extensions. Only the SHA-256 layouts are parsed, and the others
+        // are kept as unknown extensions.
+        let unknown = || Self::Unknown { id: hdr.id, raw: x.to_vec() };
+        if Self::hash_size(hdr.id, x).is_some_and(|size| size != 32) {
+            return Ok(unknown());
+        }
+
+        let ext = match hdr.id {
             0x0 => parse_ext_ent!(SystemInfo, SystemInfoExt, 
                                   IndependentPartitionEntry),
             0x1 => parse_ext_ent!(InitScript, InitScriptExt, InitScriptEntry),
@@ -94,30 +132,114 @@ impl ExtensionData {
                                   FeaturePermissionsEntry),
             0x3 => parse_ext_ent!(PartitionInfo, ManifestPartitionInfoExt, 
                                   ManifestModuleInfoExt),
-            0x4 => Self::SharedLibrary { 
-                data: SharedLibExt::from_bytes(x)
-            },
+            0x4 => parse_ext!(SharedLibrary, SharedLibExt),
             0x5 => parse_ext_ent!(ProcessAttrs, ManProcessExt, ProcessGroupId),
             0x6 => parse_ent!(ThreadAttrs, Thread),
             0x7 => parse_ent!(DeviceIds, Device),
             0x8 => parse_ent!(MmioRanges, MmioRange),
             0x9 => parse_ext_ent!(SpecialFiles, SpecialFileProducerExt, 
                                   SpecialFileDef),
-            0xa => Self::ModuleAttrs { 
-                data: ModAttrExt::from_bytes(x)
-            },
+            0xa => parse_ext!(ModuleAttrs, ModAttrExt),
             0xb => parse_ent!(LockedRanges, LockedRange),
-            0xc => Self::ClientSystemInfo { 
-                data: ClientSystemInfoExt::from_bytes(x) 
-            },
+            0xc => parse_ext!(ClientSystemInfo, ClientSystemInfoExt),
             0xd => parse_ent!(UserInfo, UserInfoEntry),
-            _ => panic!("Unimplemented extension ID {:08x}", hdr.id),
+            0xe => parse_ext_ent!(KeyManifest, KeyManifestExt, KeyManifestEntry),
+            0xf => parse_ext_ent!(SignedPackageInfo, SignedPackageInfoExt,
+                                  ManifestModuleInfoExt),
+            0x16 => parse_ext!(IfwiPartitionInfo, IfwiPartitionInfoExt),
+            // NOTE: Other extensions used by newer firmware (IUNIT, cAVS,
+            // boot policy, DnX, ...) aren't well understood yet.
+            _ => unknown(),
+        };
+        Ok(if ext.has_sha256_hashes() { ext } else { unknown() })
+    }
+
+    /// Return the size of the first hash in an extension which may use
+    /// SHA-384 (or `None` for other extensions, or if there's no hash).
+    fn hash_size(id: u32, x: &[u8]) -> Option<usize> {
+        // Offset and length of the hash size field
+        let (offset, len) = match id {
+            0xe => (KeyManifestExt::SIZE + 34, 2),
+            0xf => (SignedPackageInfoExt::SIZE + 14, 2),
+            0x16 => (25, 3),
+            _ => return None,
+        };
+        let field = x.get(offset..offset + len)?;
+        Some(field.iter().rev().fold(0, |acc, b| (acc << 8) | *b as usize))
+    }
+
+    /// Return whether all of the hashes in this extension are SHA-256
+    /// digests (according to their hash size fields).
+    fn has_sha256_hashes(&self) -> bool {
+        match self {
+            Self::KeyManifest { entries, .. } => {
+                entries.iter().all(|e| e.hash_size() == 32)
+            },
+            Self::SignedPackageInfo { entries, .. } => {
+                entries.iter().all(|e| e.hash_size() == 32)
+            },
+            Self::IfwiPartitionInfo { data } => data.hash_size() == 32,
+            _ => true,
+        }
+    }
+}
+impl crate::ToBytes for ExtensionData {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        fn write_all<T: Field>(entries: &[T], out: &mut Vec<u8>) {
+            entries.iter().for_each(|e| e.write(out));
+        }
+        match self {
+            Self::SystemInfo { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::InitScript { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::FeaturePermissions { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::PartitionInfo { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::SharedLibrary { data } => data.write(out),
+            Self::ProcessAttrs { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::ThreadAttrs { entries } => write_all(entries, out),
+            Self::DeviceIds { entries } => write_all(entries, out),
+            Self::MmioRanges { entries } => write_all(entries, out),
+            Self::SpecialFiles { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::ModuleAttrs { data } => data.write(out),
+            Self::LockedRanges { entries } => write_all(entries, out),
+            Self::ClientSystemInfo { data } => data.write(out),
+            Self::UserInfo { entries } => write_all(entries, out),
+            Self::KeyManifest { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::SignedPackageInfo { data, entries } => {
+                data.write(out); write_all(entries, out);
+            },
+            Self::IfwiPartitionInfo { data } => data.write(out),
+            Self::Unknown { raw, .. } => out.extend_from_slice(raw),
         }
     }
 }
 
+/// Decode an array of entries filling all of `x`.
+fn read_entries<T: FromBytes>(x: &[u8]) -> crate::Result<Vec<T>> {
+    if !x.len().is_multiple_of(T::SIZE) {
+        return Err(Error::Invalid {
+            name: crate::error::type_name::<T>(), offset: 0,
+            reason: "extension length isn't a multiple of the entry size",
+        });
+    }
+    crate::read_array(x, x.len() / T::SIZE)
+}
+
+le_struct! {
 /// Extension ID 0x0000_0000
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct SystemInfoExt {
     min_uma_size: u32,
@@ -127,22 +249,25 @@ pub struct SystemInfoExt {
     reserved_0: u64,
     reserved_1: u32,
 }
+}
 impl crate::FromBytes for SystemInfoExt {}
 
+le_struct! {
 /// Extension ID 0x0000_0001
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct InitScriptExt { reserved: u32, num_modules: u32 }
+}
 impl crate::FromBytes for InitScriptExt {}
 
+le_struct! {
 /// Extension ID 0x0000_0002
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct FeaturePermissionsExt { num_modules: u32 }
+}
 impl crate::FromBytes for FeaturePermissionsExt {}
 
+le_struct! {
 /// Extension ID 0x0000_0003
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ManifestPartitionInfoExt {
     part_name: u32,
@@ -155,10 +280,19 @@ pub struct ManifestPartitionInfoExt {
     flags: u32,
     reserved: [u8; 20],
 }
+}
 impl crate::FromBytes for ManifestPartitionInfoExt {}
+impl ManifestPartitionInfoExt {
+    /// Name of the partition (i.e. `FTPR`).
+    pub fn part_name(&self) -> [u8; 4] { self.part_name.to_le_bytes() }
+    /// Length of the partition in bytes.
+    pub fn part_len(&self) -> usize { self.part_len as usize }
+    /// SHA-256 digest of the partition.
+    pub fn part_digest(&self) -> [u8; 32] { self.part_sha256_digest }
+}
 
+le_struct! {
 /// Extension ID 0x0000_0004
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct SharedLibExt {
     context_size: u32,
@@ -167,10 +301,11 @@ pub struct SharedLibExt {
     tls_size: u32,
     reserved: u32,
 }
+}
 impl crate::FromBytes for SharedLibExt {}
 
+le_struct! {
 /// Extension ID 0x0000_0005
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ManProcessExt {
     flags: ManProcessExtFlags,
@@ -187,16 +322,18 @@ pub struct ManProcessExt {
     reserved_1: u16,
     reserved_2: u64,
 }
+}
 impl crate::FromBytes for ManProcessExt {}
 
+le_struct! {
 /// Extension ID 0x0000_0009
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct SpecialFileProducerExt { dev_major_id: u16, flags: u16 }
+}
 impl crate::FromBytes for SpecialFileProducerExt {}
 
+le_struct! {
 /// Extension ID 0x0000_000a
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ModAttrExt {
     /// 0 - Uncompressed 1 - Huffman Compressed 2 - LZMA Compressed
@@ -211,10 +348,11 @@ pub struct ModAttrExt {
     pub sha256_digest: [u8; 32],
     //pub sha256_digest: [u32; 8],
 }
+}
 impl crate::FromBytes for ModAttrExt {}
 impl ModAttrExt {
-    pub fn compression_type(&self) -> CompressionType {
-        CompressionType::from(self.compression_type)
+    pub fn compression_type(&self) -> crate::Result<CompressionType> {
+        CompressionType::try_from(self.compression_type)
     }
     pub fn compressed_size(&self) -> usize {
         self.compressed_size as usize
@@ -225,31 +363,105 @@ impl ModAttrExt {
 
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum CompressionType { None = 0, Huff = 1, Lzma = 2 }
-impl From<u8> for CompressionType {
-    fn from(x: u8) -> Self {
+impl TryFrom<u8> for CompressionType {
+    type Error = Error;
+    fn try_from(x: u8) -> crate::Result<Self> {
         match x {
-            0 => Self::None, 1 => Self::Huff, 2 => Self::Lzma,
-            _ => unreachable!(),
+            0 => Ok(Self::None), 1 => Ok(Self::Huff), 2 => Ok(Self::Lzma),
+            _ => Err(Error::UnknownCompression {
+                name: "ModAttrExt", offset: 0, kind: x,
+            }),
         }
     }
 }
 
+le_struct! {
 /// Extension ID 0x0000_000c
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ClientSystemInfoExt {
     sku_cap: u32,
     sku_cap_reserved: [u8; 28],
     sku_attrs: u64,
 }
+}
 impl crate::FromBytes for ClientSystemInfoExt {}
 
+le_struct! {
+/// Extension ID 0x0000_000e
+#[derive(Clone, Copy, Debug)]
+pub struct KeyManifestExt {
+    key_type: u32,
+    key_svn: u32,
+    oem_id: u16,
+    key_id: u8,
+    reserved0: u8,
+    reserved1: [u8; 16],
+}
+}
+impl crate::FromBytes for KeyManifestExt {}
+impl KeyManifestExt {
+    /// Security version number of the key manifest.
+    pub fn key_svn(&self) -> u32 { self.key_svn }
+    pub fn oem_id(&self) -> u16 { self.oem_id }
+    pub fn key_id(&self) -> u8 { self.key_id }
+}
 
+le_struct! {
+/// Extension ID 0x0000_000f
+#[derive(Clone, Copy, Debug)]
+pub struct SignedPackageInfoExt {
+    partition_name: [u8; 4],
+    version_control_number: u32,
+    usage_bitmap: [u8; 16],
+    arb_svn: u32,
+    reserved: [u8; 16],
+}
+}
+impl crate::FromBytes for SignedPackageInfoExt {}
+impl SignedPackageInfoExt {
+    /// Name of the partition (i.e. `FTPR`).
+    pub fn partition_name(&self) -> [u8; 4] { self.partition_name }
+    /// Anti-rollback security version number.
+    pub fn arb_svn(&self) -> u32 { self.arb_svn }
+}
 
-#[repr(C)]
+le_struct! {
+/// Extension ID 0x0000_0016
+#[derive(Clone, Copy, Debug)]
+pub struct IfwiPartitionInfoExt {
+    partition_name: [u8; 4],
+    partition_size: u32,
+    partition_version: u32,
+    data_format_version: u32,
+    instance_id: u32,
+    flags: u32,
+    hash_algorithm: u8,
+    hash_size: [u8; 3],
+    hash: [u8; 32],
+    reserved: [u8; 20],
+}
+}
+impl crate::FromBytes for IfwiPartitionInfoExt {}
+impl IfwiPartitionInfoExt {
+    /// Name of the partition (i.e. `FTPR`).
+    pub fn partition_name(&self) -> [u8; 4] { self.partition_name }
+    /// Original size of the partition in bytes.
+    pub fn partition_size(&self) -> usize { self.partition_size as usize }
+    /// Size of the hash in bytes.
+    pub fn hash_size(&self) -> usize {
+        let [a, b, c] = self.hash_size;
+        u32::from_le_bytes([a, b, c, 0]) as usize
+    }
+    /// Digest of the partition (everything except for the manifest).
+    pub fn hash(&self) -> [u8; 32] { self.hash }
+}
+
+
+
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct IndependentPartitionEntry {
     name: [u8; 4],
@@ -257,9 +469,10 @@ pub struct IndependentPartitionEntry {
     user_id: u16,
     reserved: u16,
 }
+}
 impl crate::FromBytes for IndependentPartitionEntry {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct InitScriptEntry {
     partition_name: [u8; 4],
@@ -267,36 +480,54 @@ pub struct InitScriptEntry {
     init_flags: u32,
     boot_type: u32,
 }
+}
 impl crate::FromBytes for InitScriptEntry {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct FeaturePermissionsEntry { user_id: u16, reserved: u16 }
+}
 impl crate::FromBytes for FeaturePermissionsEntry {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct ManifestModuleInfoExt {
     name: [u8; 12],
     kind: u8,
-    reserved0: u8,
-    reserved1: u16,
+    hash_algorithm: u8,
+    hash_size: u16,
     metadata_size: u32,
     metadata_sha256_digest: [u8; 32],
 }
+}
 impl crate::FromBytes for ManifestModuleInfoExt {}
+impl ManifestModuleInfoExt {
+    /// Name of the module described by this entry.
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name)
+            .trim_end_matches(char::from(0)).to_owned()
+    }
+    /// Size of the digest of the metadata file in bytes.
+    pub fn hash_size(&self) -> usize { self.hash_size as usize }
+    /// Size of the module metadata (`.met`) file in bytes.
+    pub fn metadata_size(&self) -> usize { self.metadata_size as usize }
+    /// SHA-256 digest of the module metadata file.
+    pub fn metadata_digest(&self) -> [u8; 32] { self.metadata_sha256_digest }
+}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct ProcessGroupId { group_id: u16, }
+}
 impl crate::FromBytes for ProcessGroupId {}
 
-#[repr(transparent)]
+le_struct! {
 #[derive(Clone, Copy, Debug)]
 pub struct ManProcessExtFlags(pub u32);
+}
 impl ManProcessExtFlags {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Thread {
     stack_size: u32,
@@ -304,24 +535,28 @@ pub struct Thread {
     scheduling_policy: u32,
     reserved: u32,
 }
+}
 impl crate::FromBytes for Thread {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct LockedRange { range_base: u32, range_size: u32 }
+}
 impl crate::FromBytes for LockedRange {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Device { device_id: u32, reserved: u32 }
+}
 impl crate::FromBytes for Device {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct MmioRange { base: u32, size: u32, flags: u32 }
+}
 impl crate::FromBytes for MmioRange {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct SpecialFileDef {
     name: [u8; 12],
@@ -332,9 +567,10 @@ pub struct SpecialFileDef {
     reserved0: u8,
     reserved1: u32,
 }
+}
 impl crate::FromBytes for SpecialFileDef {}
 
-#[repr(C)]
+le_struct! {
 #[derive(Clone, Copy, Debug)]
 pub struct UserInfoEntry {
     id: u16,
@@ -344,6 +580,7 @@ pub struct UserInfoEntry {
     wop_quota: u32,
     working_dir: [u8; 36],
 }
+}
 impl crate::FromBytes for UserInfoEntry {}
 impl Default for UserInfoEntry {
     fn default() -> Self {
@@ -354,3 +591,24 @@ impl Default for UserInfoEntry {
     }
 }
 
+le_struct! {
+/// Hash of some key listed in a key manifest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyManifestEntry {
+    usage_bitmap: [u8; 16],
+    reserved0: [u8; 16],
+    flags: u8,
+    hash_algorithm: u8,
+    hash_size: u16,
+    hash: [u8; 32],
+}
+}
+impl crate::FromBytes for KeyManifestEntry {}
+impl KeyManifestEntry {
+    /// Bitmap describing what this key can be used to sign.
+    pub fn usage_bitmap(&self) -> [u8; 16] { self.usage_bitmap }
+    /// Size of the hash in bytes.
+    pub fn hash_size(&self) -> usize { self.hash_size as usize }
+    /// Hash of the key.
+    pub fn hash(&self) -> [u8; 32] { self.hash }
+}
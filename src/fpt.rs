@@ -1,6 +1,6 @@
 
 use std::fmt;
-use crate::FromBytes;
+use crate::{ FromBytes, ToBytes, Error, bytes::Field, checksum::Checksum };
 
 /// A flash partition table describing partitions in some CSME image.
 pub struct FlashPartitionTable {
@@ -8,26 +8,64 @@ pub struct FlashPartitionTable {
     pub entries: Vec<FptEntry>,
 }
 impl FlashPartitionTable {
-    pub fn from_bytes(data: &[u8]) -> Self {
-        let header = FptHeader::from_bytes(&data[0x00..0x20]);
+    pub fn from_bytes(data: &[u8]) -> crate::Result<Self> {
+        let header = FptHeader::from_bytes(data)?;
         let num_entries = header.num_fpt_entries as usize;
-        assert!(num_entries <= 127);
-        let mut entries = vec![FptEntry::default(); num_entries];
-        unsafe {
-            entries.copy_from_slice(
-                std::slice::from_raw_parts(
-                    data[0x20..].as_ptr() as *const FptEntry, 
-                    num_entries
-                ),
-            );
+        if num_entries > 127 {
+            return Err(Error::Invalid {
+                name: "FptHeader", offset: 0,
+                reason: "too many partition table entries",
+            });
+        }
+        let entries = crate::read_array(&data[FptHeader::SIZE..], num_entries)
+            .map_err(|e| e.shift(FptHeader::SIZE))?;
+        Ok(FlashPartitionTable { header, entries })
+    }
+
+    /// Return the checksum stored in the header.
+    pub fn checksum(&self) -> Checksum {
+        match self.header.version() {
+            FptHeaderVersion::V2_1 => Checksum::Crc32(self.header.flash_layout),
+            _ => Checksum::Sum8(self.header.header_checksum),
+        }
+    }
+
+    /// Compute the checksum for this table.
+    ///
+    /// Version 2.1 headers use a CRC32 over the header. Older headers use an
+    /// 8-bit checksum over the header and entries.
+    pub fn compute_checksum(&self) -> Checksum {
+        match self.header.version() {
+            FptHeaderVersion::V2_1 => Checksum::crc32(self.header.to_bytes(),
+                FptHeader::CRC32_OFFSET),
+            _ => Checksum::sum8(self.to_bytes(), FptHeader::CHECKSUM_OFFSET),
+        }
+    }
+
+    /// Return whether or not the checksum in the header is correct.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum() == self.compute_checksum()
+    }
+
+    /// Recompute the checksum and store it in the header.
+    pub fn update_checksum(&mut self) {
+        match self.compute_checksum() {
+            Checksum::Sum8(x) => self.header.header_checksum = x,
+            Checksum::Crc32(x) => self.header.flash_layout = x,
         }
-        FlashPartitionTable { header, entries }
+    }
+}
+
+impl crate::ToBytes for FlashPartitionTable {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        self.header.write(out);
+        self.entries.iter().for_each(|e| e.write(out));
     }
 }
 
 
+le_struct! {
 /// Flash partition table header.
-#[repr(C)]
 #[derive(Debug)]
 pub struct FptHeader {
     pub marker: [u8; 4],
@@ -35,29 +73,136 @@ pub struct FptHeader {
     pub header_version: u8,
     pub entry_version: u8,
     pub header_length: u8,
+    /// 8-bit checksum (flags in version 2.1 headers).
     pub header_checksum: u8,
     pub ticks_to_add: u16,
     pub tokens_to_add: u16,
     pub reserved: u32,
+    /// Flash layout (CRC32 in version 2.1 headers).
     pub flash_layout: u32,
     pub fitc_major_ver: u16,
     pub fitc_minor_ver: u16,
     pub fitc_hotfix_ver: u16,
     pub fitc_build_ver: u16,
 }
+}
 impl FptHeader {
     const MARKER_FPT: [u8; 4] = *b"$FPT";
+    const CHECKSUM_OFFSET: usize = 0x0b;
+    const CRC32_OFFSET: usize = 0x14;
+
+    /// Return the version of this header.
+    pub fn version(&self) -> FptHeaderVersion {
+        FptHeaderVersion::from(self.header_version)
+    }
+    /// Return the layout of the flash device.
+    ///
+    /// Version 2.1 headers don't have a layout (this field holds the CRC32).
+    pub fn flash_layout(&self) -> Option<FlashLayout> {
+        match self.version() {
+            FptHeaderVersion::V2_1 => None,
+            _ => Some(FlashLayout::from(self.flash_layout)),
+        }
+    }
+    /// Return the flags from a version 2.1 header.
+    pub fn flags(&self) -> Option<FptFlags> {
+        match self.version() {
+            FptHeaderVersion::V2_1 => Some(FptFlags(self.header_checksum)),
+            _ => None,
+        }
+    }
+    /// Return the flash wear-out protection parameters.
+    pub fn tokens(&self) -> FlashTokens {
+        FlashTokens { ticks: self.ticks_to_add, tokens: self.tokens_to_add }
+    }
+    /// Return the version of the tool (FITC) used to build this image.
+    pub fn fitc_version(&self) -> FitcVersion {
+        FitcVersion {
+            major: self.fitc_major_ver,
+            minor: self.fitc_minor_ver,
+            hotfix: self.fitc_hotfix_ver,
+            build: self.fitc_build_ver,
+        }
+    }
+}
+
+/// Version of the flash partition table header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FptHeaderVersion {
+    /// ME 6-10
+    V1_0,
+    /// CSME 11
+    V2_0,
+    /// CSME 12 and later
+    V2_1,
+    Unknown(u8),
+}
+impl From<u8> for FptHeaderVersion {
+    fn from(x: u8) -> Self {
+        match x {
+            0x10 => Self::V1_0, 0x20 => Self::V2_0, 0x21 => Self::V2_1,
+            _ => Self::Unknown(x),
+        }
+    }
+}
+
+/// Layout of the flash device holding the firmware.
+///
+/// NOTE: We've only seen images with everything on SPI flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashLayout {
+    Spi,
+    Unknown(u32),
+}
+impl From<u32> for FlashLayout {
+    fn from(x: u32) -> Self {
+        match x { 0 => Self::Spi, _ => Self::Unknown(x) }
+    }
+}
+
+/// Flags from a version 2.1 FPT header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FptFlags(pub u8);
+impl FptFlags {
+    /// Set when the image contains a backup copy of the FPT.
+    pub fn backup_present(&self) -> bool { self.0 & 1 != 0 }
+}
+
+/// Parameters for flash wear-out protection: `tokens` are added to the
+/// budget for writes every `ticks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashTokens { pub ticks: u16, pub tokens: u16 }
+
+/// Version of the tool (FITC) used to build an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FitcVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub hotfix: u16,
+    pub build: u16,
+}
+impl fmt::Display for FitcVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}.{}.{}.{}", self.major, self.minor, self.hotfix, self.build)
+    }
 }
 impl crate::FromBytes for FptHeader {
-    fn validate(&self) -> Result<(), &'static str> {
-        assert_eq!(self.marker, Self::MARKER_FPT);
-        assert_eq!(self.header_length, 0x20);
+    fn validate(&self) -> crate::Result<()> {
+        if self.marker != Self::MARKER_FPT {
+            return Err(Error::BadMagic { name: "FptHeader", offset: 0 });
+        }
+        if self.header_length != 0x20 {
+            return Err(Error::Invalid {
+                name: "FptHeader", offset: 0,
+                reason: "unexpected header length",
+            });
+        }
         Ok(())
     }
 }
 
+le_struct! {
 /// An entry in the flash partition table.
-#[repr(C)]
 #[derive(Copy, Clone, Default)]
 pub struct FptEntry {
     pub name: [u8; 4],
@@ -69,13 +214,13 @@ pub struct FptEntry {
     pub reserved3: u32,
     pub attrs: FptEntryAttributes,
 }
+}
+impl crate::FromBytes for FptEntry {}
 impl fmt::Debug for FptEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        let name = std::str::from_utf8(&self.name)
-            .expect("Couldn't interpret partition name as UTF-8")
-            .trim_end_matches(char::from(0));
+        let name = String::from_utf8_lossy(&self.name);
         f.debug_struct("FptEntry")
-            .field("name", &name)
+            .field("name", &name.trim_end_matches(char::from(0)))
             .field("kind", &self.attrs.kind())
             .field("valid", &self.attrs.entry_valid())
             .field("offset", &self.offset)
@@ -84,33 +229,51 @@ impl fmt::Debug for FptEntry {
     }
 }
 
+le_struct! {
 /// Attributes bitfield for an FPT entry.
-#[repr(transparent)]
 #[derive(Copy, Clone, Default, Debug)]
 pub struct FptEntryAttributes(pub u32);
+}
 impl FptEntryAttributes {
-    /// Return the particular type of this partition.
-    pub fn kind(&self) -> PartitionType { 
-        PartitionType::from(self.0 & 0x0000_003f)
+    /// Return the particular type of this partition (bits 0-6).
+    pub fn kind(&self) -> PartitionType {
+        PartitionType::from(self.0 & 0x0000_007f)
     }
+    /// Reserved bits 7-14.
+    pub fn reserved0(&self) -> u32 { (self.0 & 0x0000_7f80) >> 7 }
+    /// Block write lock 0 (bit 15).
+    pub fn bwl0(&self) -> bool { (self.0 & 0x0000_8000) != 0 }
+    /// Block write lock 1 (bit 16).
+    pub fn bwl1(&self) -> bool { (self.0 & 0x0001_0000) != 0 }
+    /// Reserved bits 17-23.
+    pub fn reserved1(&self) -> u32 { (self.0 & 0x00fe_0000) >> 17 }
+    /// The "entry valid" byte (bits 24-31).
+    pub fn valid_byte(&self) -> u8 { (self.0 >> 24) as u8 }
     // Return whether or not this partition is valid.
-    pub fn entry_valid(&self) -> bool { 
-        if ((self.0 & 0xff00_0000) == 0xff00_0000) { false } else { true } 
+    pub fn entry_valid(&self) -> bool {
+        self.valid_byte() != 0xff
     }
 }
 
 /// Representing different types of partitions represented in a table.
-#[repr(u8)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PartitionType {
-    Code = 0x00,
-    Data = 0x01,
+    Code,
+    Data,
+    Nvram,
+    Generic,
+    Effs,
+    Rom,
+    /// Some partition type we don't know about.
+    Unknown(u32),
 }
 impl From<u32> for PartitionType {
     fn from(x: u32) -> Self {
-        match x { 
+        match x {
             0 => PartitionType::Code, 1 => PartitionType::Data,
-            _ => panic!("Unimplemented partition type {}", x),
+            2 => PartitionType::Nvram, 3 => PartitionType::Generic,
+            4 => PartitionType::Effs, 5 => PartitionType::Rom,
+            _ => PartitionType::Unknown(x),
         }
     }
 }
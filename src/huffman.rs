@@ -1,104 +1,490 @@
-use crate::ext::ModAttrExt;
-use std::collections::{ HashMap, BTreeMap };
+//! Huffman compression used for some modules.
+//!
+//! Chunks of compressed data are decoded with one of two dictionaries (for
+//! code and data), selected by the flags in the header. The dictionaries
+//! for CSME11 are built in. Dictionaries for other generations can be
+//! loaded from text files with one codeword per line:
+//!
+//! ```text
+//! # <codeword (in binary)> <symbol (in hex)>
+//! 1111111 00000000
+//! 11101100 ffffffffffffffffffffffffffffff
+//! ```
+//!
+//! NOTE: The tables used by ME 6-10 aren't included here.
 
+use crate::{ ext::ModAttrExt, Error, bytes::Field };
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+le_struct! {
 /// Header entry describing a chunk in Huffman-compressed data.
-#[repr(transparent)]
 struct HuffmanHeaderEntry(pub u32);
+}
+impl crate::FromBytes for HuffmanHeaderEntry {}
 impl HuffmanHeaderEntry {
+    /// Flag selecting the data dictionary.
+    ///
+    /// NOTE: We assume that bit 0 of the flags selects the data dictionary.
+    const DATA_FLAG: u32 = 1;
+
+    fn new(offset: u32, flags: u32) -> Self { Self(offset | (flags << 25)) }
+
     // Offset to the compressed data for this entry.
     pub fn offset(&self) -> usize { (self.0 & 0x01ff_ffff) as usize }
 
     // Indicates whether to use the code/data dictionary.
-    //
-    // NOTE: For CSME11, it seems like the two dictionaries are the same.
-    pub fn flags(&self) -> usize { ((self.0 >> 25) & 0x7f) as usize }
+    pub fn flags(&self) -> u32 { (self.0 >> 25) & 0x7f }
 }
 
-pub fn decompress_huff(src: &[u8], attr: &ModAttrExt) -> Vec<u8> {
-    const CHUNK_LEN: usize = 0x0000_1000;
-    let num_chunks =  attr.uncompressed_size() / CHUNK_LEN;
-
-    let mut output_buffer = Vec::new();
-
-    let dict = build_code_dictionary();
+/// A set of codewords and the symbols they decode to.
+#[derive(Clone, Debug)]
+pub struct HuffmanDictionary {
+    /// Every codeword in this dictionary.
+    codewords: Vec<Codeword>,
+    /// Decoded bytes for all codewords.
+    symbol_data: Vec<u8>,
+    /// First-level lookup table, indexed by the next [Self::LUT_BITS] bits.
+    /// Entries are `None` for codewords that don't fit in the table.
+    lut: Vec<Option<(u32, u32)>>,
+    /// Tables for codewords longer than [Self::LUT_BITS] bits (in order of
+    /// increasing length).
+    long: Vec<LongCodewords>,
+}
 
-    let header_len = num_chunks * std::mem::size_of::<u32>();
-    let header_slice = unsafe { std::slice::from_raw_parts(
-            src.as_ptr() as *const HuffmanHeaderEntry, num_chunks
-    )};
-    let data_slice = &src[header_len..];
+/// A codeword and the location of its symbol in the dictionary.
+#[derive(Clone, Copy, Debug)]
+struct Codeword { bitlen: u32, codeword: u32, start: usize, end: usize }
 
-    for (idx, ent) in header_slice.iter().enumerate() {
-        let foo = &data_slice[0..0x20];
+/// Codewords of some length, which don't fit in the first-level table.
+#[derive(Clone, Debug)]
+struct LongCodewords {
+    bitlen: u32,
+    /// The smallest codeword with this length (aligned to the top of a
+    /// `u32`).
+    min: u32,
+    /// Index of each codeword (from the smallest) in the dictionary.
+    index: Vec<Option<u32>>,
+}
 
-        let mut cur = ent.offset();
+impl HuffmanDictionary {
+    /// Length of the longest codeword we can decode.
+    const MAX_CODEWORD_LEN: u32 = 24;
+    /// Number of bits used to index the first-level table.
+    const LUT_BITS: u32 = 9;
 
-        let mut cursor_head = idx * CHUNK_LEN;
-        let mut cursor_tail = (idx + 1) * CHUNK_LEN;
+    /// Build a dictionary from a list of (bit_length, codeword, symbol).
+    ///
+    /// Longer codewords must have smaller values than shorter ones (like
+    /// the tables used by Intel), so that the length of the next codeword
+    /// can be found by comparing against the smallest codeword of each
+    /// length.
+    pub fn new(codewords: impl IntoIterator<Item=(u32, u32, Vec<u8>)>)
+        -> crate::Result<Self>
+    {
+        let invalid = |reason| Error::Invalid {
+            name: "HuffmanDictionary", offset: 0, reason
+        };
 
-        let mut bit_buffer: u32 = 0;
-        let mut bit_avail: u32 = 0;
-        while cursor_head < cursor_tail {
+        let mut res = Self {
+            codewords: Vec::new(), symbol_data: Vec::new(),
+            lut: vec![None; 1 << Self::LUT_BITS], long: Vec::new(),
+        };
+        for (bitlen, codeword, symbol) in codewords {
+            if bitlen == 0 || bitlen > Self::MAX_CODEWORD_LEN {
+                return Err(invalid("unsupported codeword length"));
+            }
+            if codeword >> bitlen != 0 {
+                return Err(invalid("codeword is longer than its length"));
+            }
+            if symbol.is_empty() {
+                return Err(invalid("codeword has an empty symbol"));
+            }
+            let start = res.symbol_data.len();
+            res.symbol_data.extend_from_slice(&symbol);
+            res.codewords.push(Codeword {
+                bitlen, codeword, start, end: res.symbol_data.len()
+            });
+        }
+        res.codewords.sort_by_key(|c| (c.bitlen, c.codeword));
+        if res.codewords.windows(2).any(|w| {
+            (w[0].bitlen, w[0].codeword) == (w[1].bitlen, w[1].codeword)
+        }) {
+            return Err(invalid("duplicate codeword"));
+        }
 
-            // Read 32 bits in big-endian ordering
-            while bit_avail <= 24 && cur < data_slice.len() {
-                bit_buffer = bit_buffer 
-                    | (data_slice[cur] as u32) << (24 - bit_avail);
-                cur += 1;
-                bit_avail += 8;
+        // For each length, the smallest codeword with that length
+        let mut shape: Vec<(u32, u32)> = Vec::new();
+        for c in res.codewords.iter() {
+            let value = c.codeword << (32 - c.bitlen);
+            match shape.last_mut() {
+                Some((min, len)) if *len == c.bitlen => *min = (*min).min(value),
+                _ => shape.push((value, c.bitlen)),
             }
+        }
+        // Each codeword must be in the range for its length
+        for c in res.codewords.iter() {
+            let value = c.codeword << (32 - c.bitlen);
+            if shape.iter().find(|(min, _)| value >= *min)
+                .map(|(_, len)| *len) != Some(c.bitlen)
+            {
+                return Err(invalid("codewords aren't ordered by length"));
+            }
+        }
 
-            // Find the length and base of the next codeword
-            let mut codeword_length = None;
-            let mut codeword_base = None;
-            for (shape, len, base) in &SHAPE {
-                if bit_buffer >= *shape {
-                    codeword_length = Some(*len);
-                    codeword_base = Some(*base);
-                    break;
+        for (idx, c) in res.codewords.iter().enumerate() {
+            if c.bitlen <= Self::LUT_BITS {
+                // Fill every entry beginning with this codeword
+                let free_bits = Self::LUT_BITS - c.bitlen;
+                let first = (c.codeword << free_bits) as usize;
+                for entry in &mut res.lut[first..first + (1 << free_bits)] {
+                    *entry = Some((c.bitlen, idx as u32));
                 }
+                continue;
             }
-            assert!(codeword_base.is_some());
-            assert!(codeword_length.is_some());
+            let (min, _) = shape.iter().find(|(_, len)| *len == c.bitlen)
+                .copied().unwrap_or_default();
+            if res.long.last().map(|l| l.bitlen) != Some(c.bitlen) {
+                res.long.push(LongCodewords {
+                    bitlen: c.bitlen, min, index: Vec::new(),
+                });
+            }
+            let long = res.long.last_mut().unwrap();
+            let pos = (c.codeword - (min >> (32 - c.bitlen))) as usize;
+            if long.index.len() <= pos {
+                long.index.resize(pos + 1, None);
+            }
+            long.index[pos] = Some(idx as u32);
+        }
+        Ok(res)
+    }
 
-            if bit_avail >= codeword_length.unwrap() {
-                let codeword = bit_buffer >> (32 - codeword_length.unwrap());
-                bit_buffer = bit_buffer << codeword_length.unwrap();
-                bit_avail  = bit_avail - codeword_length.unwrap();
+    /// Parse a dictionary from a text file (see the module documentation).
+    pub fn load(text: &str) -> crate::Result<Self> {
+        let mut codewords = Vec::new();
+        for (offset, line) in crate::text_lines(text) {
+            let mut fields = line.split_whitespace();
+            let codeword = fields.next().and_then(|x| {
+                u32::from_str_radix(x, 2).ok().map(|c| (x.len() as u32, c))
+            });
+            let symbol = fields.next().and_then(|x| hex::decode(x).ok());
+            match (codeword, symbol, fields.next()) {
+                (Some((bitlen, codeword)), Some(symbol), None) =>
+                    codewords.push((bitlen, codeword, symbol)),
+                _ => return Err(Error::Invalid {
+                    name: "HuffmanDictionary", offset,
+                    reason: "expected a binary codeword and a hex symbol",
+                }),
+            }
+        }
+        Self::new(codewords)
+    }
+
+    /// The dictionary used by CSME11.
+    pub fn csme11() -> Self {
+        let codewords = build_code_dictionary().into_iter()
+            .map(|((bitlen, codeword), symbol)| (bitlen, codeword, symbol.to_vec()));
+        Self::new(codewords).expect("invalid built-in dictionary")
+    }
+
+    /// Return every (bit_length, codeword, symbol) in this dictionary.
+    pub fn codewords(&self) -> impl Iterator<Item=(u32, u32, &[u8])> {
+        self.codewords.iter().map(move |c| {
+            (c.bitlen, c.codeword, &self.symbol_data[c.start..c.end])
+        })
+    }
+
+    /// Decode the codeword at the top of `bit_buffer`, returning the length
+    /// of the codeword and the index of its symbol.
+    ///
+    /// If there's no such codeword, this returns the length that the
+    /// codeword would have.
+    #[inline]
+    fn decode(&self, bit_buffer: u32) -> (u32, Option<usize>) {
+        let lut_idx = (bit_buffer >> (32 - Self::LUT_BITS)) as usize;
+        if let Some((bitlen, idx)) = self.lut[lut_idx] {
+            return (bitlen, Some(idx as usize));
+        }
+        let long = match self.long.iter().find(|l| bit_buffer >= l.min) {
+            Some(long) => long,
+            None => return (Self::MAX_CODEWORD_LEN, None),
+        };
+        let pos = ((bit_buffer - long.min) >> (32 - long.bitlen)) as usize;
+        let idx = long.index.get(pos).copied().flatten();
+        (long.bitlen, idx.map(|idx| idx as usize))
+    }
+
+    /// Return the symbol for the codeword at some index.
+    #[inline]
+    fn symbol(&self, idx: usize) -> &[u8] {
+        let c = &self.codewords[idx];
+        &self.symbol_data[c.start..c.end]
+    }
+
+    /// Return a hashmap from decoded bytes to (bit_length, codeword).
+    fn encoder(&self) -> HashMap<&[u8], (u32, u32)> {
+        self.codewords()
+            .map(|(bitlen, codeword, symbol)| (symbol, (bitlen, codeword)))
+            .collect()
+    }
+}
+
+/// The pair of dictionaries used to decode some compressed data.
+#[derive(Clone, Debug)]
+pub struct HuffmanDictionaries {
+    pub code: HuffmanDictionary,
+    pub data: HuffmanDictionary,
+}
+impl HuffmanDictionaries {
+    /// The dictionaries used by CSME11.
+    ///
+    /// These are only built the first time they're used.
+    ///
+    /// NOTE: For CSME11, it seems like the two dictionaries are the same.
+    pub fn csme11() -> &'static Self {
+        static CSME11: OnceLock<HuffmanDictionaries> = OnceLock::new();
+        CSME11.get_or_init(|| {
+            let code = HuffmanDictionary::csme11();
+            Self { data: code.clone(), code }
+        })
+    }
 
-                //let length_tbl = dict.get(&codeword_length.unwrap()).unwrap();
-                //let symbol = length_tbl.get(&codeword).unwrap();
+    /// Return the dictionary selected by the flags in a header entry.
+    pub fn select(&self, flags: u32) -> &HuffmanDictionary {
+        if flags & HuffmanHeaderEntry::DATA_FLAG != 0 {
+            &self.data
+        } else {
+            &self.code
+        }
+    }
+}
 
-                let symbol = dict.get(&(codeword_length.unwrap(), codeword))
-                    .unwrap();
+/// Length of each independently-compressed chunk (page) of data.
+pub const CHUNK_LEN: usize = 0x0000_1000;
 
-                let symbol_len = symbol.len();
-                if cursor_tail - cursor_head >= symbol_len {
-                    output_buffer.extend_from_slice(symbol);
-                    cursor_head += symbol_len;
-                } else {
-                    panic!("codeword {:08x} would overflow", codeword);
+/// Decompress `src` with the CSME11 dictionaries.
+pub fn decompress_huff(src: &[u8], attr: &ModAttrExt) -> crate::Result<Vec<u8>> {
+    decompress_huff_with(src, attr, HuffmanDictionaries::csme11())
+}
+
+/// Decompress `src` with some set of dictionaries.
+pub fn decompress_huff_with(src: &[u8], attr: &ModAttrExt,
+    dicts: &HuffmanDictionaries) -> crate::Result<Vec<u8>>
+{
+    let num_chunks = num_chunks(attr);
+    decompress_huff_pages(src, attr, dicts, 0..num_chunks)
+}
+
+/// Decompress a single 4KiB page from `src`.
+///
+/// The last page is shorter when the size of the module isn't a multiple
+/// of 4KiB.
+pub fn decompress_huff_page(src: &[u8], attr: &ModAttrExt,
+    dicts: &HuffmanDictionaries, page: usize) -> crate::Result<Vec<u8>>
+{
+    decompress_huff_pages(src, attr, dicts, page..page + 1)
+}
+
+/// Decompress a range of 4KiB pages from `src`, without decoding the rest.
+pub fn decompress_huff_pages(src: &[u8], attr: &ModAttrExt,
+    dicts: &HuffmanDictionaries, pages: Range<usize>) -> crate::Result<Vec<u8>>
+{
+    let size = attr.uncompressed_size();
+    let num_chunks = num_chunks(attr);
+    if pages.start > pages.end || pages.end > num_chunks {
+        return Err(Error::Invalid {
+            name: "HuffmanHeaderEntry", offset: 0,
+            reason: "page range is outside of the module",
+        });
+    }
+
+    let header_len = num_chunks * HuffmanHeaderEntry::SIZE;
+    let header: Vec<HuffmanHeaderEntry> = crate::read_array(src, num_chunks)?;
+    let data = &src[header_len..];
+
+    let mut output_buffer = Vec::with_capacity(pages.len() * CHUNK_LEN);
+    for idx in pages {
+        let ent = &header[idx];
+        let start = ent.offset();
+        if start > data.len() {
+            return Err(Error::Decompression {
+                name: "HuffmanHeaderEntry", offset: idx * HuffmanHeaderEntry::SIZE,
+                reason: format!("chunk {} starts at {:#x}, past the end of \
+                    the data ({:#x} bytes)", idx, start, data.len()),
+            });
+        }
+        // Each chunk ends where the next one (in the data) begins
+        let end = header.iter().map(|e| e.offset())
+            .filter(|off| *off > start && *off <= data.len())
+            .min().unwrap_or(data.len());
+
+        let len = CHUNK_LEN.min(size - idx * CHUNK_LEN);
+        decompress_chunk(&data[start..end], dicts.select(ent.flags()), len,
+            &mut output_buffer).map_err(|(off, reason)| Error::Decompression {
+                name: "HuffmanChunk", offset: header_len + start + off,
+                reason: format!("chunk {}: {}", idx, reason),
+            })?;
+    }
+    Ok(output_buffer)
+}
+
+/// Return the number of chunks in some module.
+///
+/// NOTE: The last chunk may be shorter than [CHUNK_LEN].
+fn num_chunks(attr: &ModAttrExt) -> usize {
+    attr.uncompressed_size().div_ceil(CHUNK_LEN)
+}
+
+/// Decode `len` bytes from a chunk, appending them to `out`.
+///
+/// On failure, this returns the offset in the chunk and a description of
+/// the problem.
+fn decompress_chunk(data: &[u8], dict: &HuffmanDictionary, len: usize,
+    out: &mut Vec<u8>) -> Result<(), (usize, String)>
+{
+    let mut cur = 0;
+    let mut remaining = len;
+    let mut bit_buffer: u64 = 0;
+    let mut bit_avail: u32 = 0;
+    while remaining > 0 {
+
+        // Read up to 64 bits in big-endian ordering
+        while bit_avail <= 56 && cur < data.len() {
+            bit_buffer |= (data[cur] as u64) << (56 - bit_avail);
+            cur += 1;
+            bit_avail += 8;
+        }
+        // Offset of the next codeword in the chunk
+        let offset = cur - (bit_avail as usize).div_ceil(8);
+
+        let (codeword_length, symbol_idx) =
+            dict.decode((bit_buffer >> 32) as u32);
+        if bit_avail < codeword_length {
+            return Err((offset, format!("ended early with {:#x} bytes left",
+                remaining)));
+        }
+        let codeword = (bit_buffer >> (64 - codeword_length)) as u32;
+        bit_buffer <<= codeword_length;
+        bit_avail -= codeword_length;
+
+        let symbol = match symbol_idx {
+            Some(symbol_idx) => dict.symbol(symbol_idx),
+            None => return Err((offset,
+                format!("invalid codeword {:08x}", codeword))),
+        };
+        if remaining < symbol.len() {
+            return Err((offset,
+                format!("codeword {:08x} would overflow", codeword)));
+        }
+        out.extend_from_slice(symbol);
+        remaining -= symbol.len();
+    }
+    Ok(())
+}
+
+/// Compress `src` with the CSME11 dictionaries.
+pub fn compress_huff(src: &[u8]) -> crate::Result<Vec<u8>> {
+    compress_huff_with(src, HuffmanDictionaries::csme11())
+}
+
+/// Compress `src` with the same format used by [decompress_huff_with].
+///
+/// Each 4KiB chunk is encoded separately with whichever dictionary gives
+/// the shortest sequence of codewords that covers the whole chunk.
+pub fn compress_huff_with(src: &[u8], dicts: &HuffmanDictionaries)
+    -> crate::Result<Vec<u8>>
+{
+    const MAX_OFFSET: usize = 0x01ff_ffff;
+
+    let code = dicts.code.encoder();
+    let data_dict = dicts.data.encoder();
+    // There's no point in trying the same dictionary twice
+    let same = dicts.code.codewords().eq(dicts.data.codewords());
+
+    let mut header = Vec::new();
+    let mut data = Vec::new();
+    for chunk in src.chunks(CHUNK_LEN) {
+        if data.len() > MAX_OFFSET {
+            return Err(Error::Invalid {
+                name: "HuffmanHeaderEntry", offset: header.len(),
+                reason: "compressed data is too large",
+            });
+        }
+        let mut best = encode_chunk(chunk, &code)
+            .map(|(cost, bytes)| (cost, bytes, 0));
+        if !same {
+            if let Some((cost, bytes)) = encode_chunk(chunk, &data_dict) {
+                if best.as_ref().is_none_or(|(x, _, _)| cost < *x) {
+                    best = Some((cost, bytes, HuffmanHeaderEntry::DATA_FLAG));
                 }
-            } else {
-                panic!("stream ended early at {:08x}", cur);
             }
         }
+        let (_, bytes, flags) = best.ok_or(Error::Invalid {
+            name: "HuffmanDictionary", offset: 0,
+            reason: "dictionary can't encode the input",
+        })?;
+        HuffmanHeaderEntry::new(data.len() as u32, flags).write(&mut header);
+        data.extend_from_slice(&bytes);
     }
-    output_buffer
+
+    header.extend_from_slice(&data);
+    Ok(header)
 }
 
-const SHAPE: [(u32, u32, u32); 9] = [
-    (0xee00_0000, 7, 0x07f),
-    (0xa300_0000, 8, 0x0ed),
-    (0x5e80_0000, 9, 0x145),
-    (0x32c0_0000, 10, 0x179),
-    (0x1ae0_0000, 11, 0x195),
-    (0x0dd0_0000, 12, 0x1ad),
-    (0x0648_0000, 13, 0x1b9),
-    (0x0140_0000, 14, 0x191),
-    (0x0000_0000, 15, 0x09f),
-];
+/// Encode a chunk with the shortest sequence of codewords from some
+/// dictionary, returning the number of bits and the encoded bytes.
+///
+/// This returns `None` if the dictionary can't encode the chunk.
+fn encode_chunk(chunk: &[u8], dict: &HashMap<&[u8], (u32, u32)>)
+    -> Option<(u32, Vec<u8>)>
+{
+    let max_symbol_len = dict.keys().map(|s| s.len()).max().unwrap_or(1);
+
+    // Find the cheapest encoding for each suffix of the chunk, working
+    // backwards from the end.
+    let mut cost = vec![u32::MAX; chunk.len() + 1];
+    let mut choice = vec![(0, 0, 0); chunk.len()];
+    cost[chunk.len()] = 0;
+    for pos in (0..chunk.len()).rev() {
+        let max_len = max_symbol_len.min(chunk.len() - pos);
+        for len in 1..=max_len {
+            let (bitlen, codeword) = match dict.get(&chunk[pos..pos + len]) {
+                Some(code) => *code,
+                None => continue,
+            };
+            let total = bitlen.saturating_add(cost[pos + len]);
+            if total < cost[pos] {
+                cost[pos] = total;
+                choice[pos] = (bitlen, codeword, len);
+            }
+        }
+    }
+    if cost[0] == u32::MAX {
+        return None;
+    }
 
+    // Write codewords with the most-significant bit first
+    let mut out = Vec::with_capacity((cost[0] as usize).div_ceil(8));
+    let mut bit_buffer: u64 = 0;
+    let mut bit_avail: u32 = 0;
+    let mut pos = 0;
+    while pos < chunk.len() {
+        let (bitlen, codeword, len) = choice[pos];
+        bit_buffer = (bit_buffer << bitlen) | codeword as u64;
+        bit_avail += bitlen;
+        while bit_avail >= 8 {
+            bit_avail -= 8;
+            out.push((bit_buffer >> bit_avail) as u8);
+            bit_buffer &= (1 << bit_avail) - 1;
+        }
+        pos += len;
+    }
+    if bit_avail > 0 {
+        out.push((bit_buffer << (8 - bit_avail)) as u8);
+    }
+    Some((cost[0], out))
+}
 
 /// Return a hashmap from (bit_length, codeword) to decoded bytes.
 fn build_code_dictionary() -> HashMap<(u32, u32), &'static [u8]> {
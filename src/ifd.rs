@@ -0,0 +1,220 @@
+//! Intel flash descriptor, found at the start of a full SPI flash image.
+
+use crate::{ Error, bytes::Field };
+
+/// The different regions described by the flash descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Descriptor = 0,
+    Bios = 1,
+    Me = 2,
+    Gbe = 3,
+    PlatformData = 4,
+    DevExp = 5,
+    SecondaryBios = 6,
+    Microcode = 7,
+    Ec = 8,
+}
+impl Region {
+    /// All regions described by FLREG0-FLREG8 (in order).
+    pub const ALL: [Region; 9] = [
+        Region::Descriptor, Region::Bios, Region::Me, Region::Gbe,
+        Region::PlatformData, Region::DevExp, Region::SecondaryBios,
+        Region::Microcode, Region::Ec,
+    ];
+}
+
+/// Layout version of the flash descriptor.
+///
+/// NOTE: Like `ifdtool`, we guess this from the read clock frequency in
+/// FLCOMP, which is always 20MHz on older chipsets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorVersion {
+    /// ICH8 through 9-series chipsets.
+    V1,
+    /// 100-series (Skylake) and later.
+    V2,
+}
+impl DescriptorVersion {
+    /// Number of region registers (FLREGn) used by this version.
+    pub fn num_regions(&self) -> usize {
+        match self { Self::V1 => 5, Self::V2 => 9 }
+    }
+    /// Number of master registers (FLMSTRn) used by this version.
+    pub fn num_masters(&self) -> usize {
+        match self { Self::V1 => 3, Self::V2 => 5 }
+    }
+}
+
+le_struct! {
+/// Flash descriptor map (FLVALSIG followed by FLMAP0-FLMAP2).
+#[derive(Clone, Copy, Debug)]
+pub struct FlashMap {
+    pub signature: u32,
+    pub flmap0: u32,
+    pub flmap1: u32,
+    pub flmap2: u32,
+}
+}
+impl FlashMap {
+    const SIGNATURE: u32 = 0x0ff0_a55a;
+
+    /// Offset of the component section (FCBA).
+    pub fn fcba(&self) -> usize { ((self.flmap0 & 0xff) << 4) as usize }
+    /// Number of flash components (NC).
+    pub fn num_components(&self) -> usize {
+        ((self.flmap0 >> 8) & 0x3) as usize + 1
+    }
+    /// Offset of the region section (FRBA).
+    pub fn frba(&self) -> usize { (((self.flmap0 >> 16) & 0xff) << 4) as usize }
+    /// Number of regions (NR).
+    ///
+    /// NOTE: This isn't used by newer chipsets, see [DescriptorVersion].
+    pub fn num_regions(&self) -> usize { ((self.flmap0 >> 24) & 0x7) as usize }
+    /// Offset of the master section (FMBA).
+    pub fn fmba(&self) -> usize { ((self.flmap1 & 0xff) << 4) as usize }
+    /// Number of masters (NM).
+    pub fn num_masters(&self) -> usize { ((self.flmap1 >> 8) & 0x7) as usize }
+    /// Offset of the PCH strap section (FPSBA).
+    pub fn fpsba(&self) -> usize { (((self.flmap1 >> 16) & 0xff) << 4) as usize }
+    /// Number of PCH straps in dwords (PSL).
+    pub fn pch_strap_len(&self) -> usize { ((self.flmap1 >> 24) & 0xff) as usize }
+    /// Offset of the processor strap section (FMSBA).
+    pub fn fmsba(&self) -> usize { ((self.flmap2 & 0xff) << 4) as usize }
+    /// Number of processor straps in dwords.
+    pub fn cpu_strap_len(&self) -> usize { ((self.flmap2 >> 8) & 0xff) as usize }
+}
+impl crate::FromBytes for FlashMap {
+    fn validate(&self) -> crate::Result<()> {
+        if self.signature != Self::SIGNATURE {
+            return Err(Error::BadMagic { name: "FlashMap", offset: 0 });
+        }
+        Ok(())
+    }
+}
+
+le_struct! {
+/// Flash component register (FLCOMP).
+#[derive(Clone, Copy, Debug)]
+pub struct FlashComponents(pub u32);
+}
+impl crate::FromBytes for FlashComponents {}
+impl FlashComponents {
+    /// Encoded read clock frequency.
+    pub fn read_freq(&self) -> u32 { (self.0 >> 17) & 0x7 }
+}
+
+le_struct! {
+/// Flash region register (FLREGn).
+#[derive(Clone, Copy, Debug)]
+pub struct FlashRegion(pub u32);
+}
+impl crate::FromBytes for FlashRegion {}
+impl FlashRegion {
+    /// Offset of the first byte in this region.
+    pub fn base(&self) -> usize { ((self.0 & 0x7fff) << 12) as usize }
+    /// Offset of the last byte in this region.
+    pub fn limit(&self) -> usize {
+        ((((self.0 >> 16) & 0x7fff) << 12) | 0xfff) as usize
+    }
+    /// Length of this region in bytes.
+    pub fn len(&self) -> usize {
+        if self.is_empty() { 0 } else { self.limit() + 1 - self.base() }
+    }
+    /// Returns `true` for regions which aren't present in the image.
+    pub fn is_empty(&self) -> bool { self.base() > self.limit() }
+}
+
+le_struct! {
+/// Flash master register (FLMSTRn) describing the access permissions
+/// granted to some master.
+#[derive(Clone, Copy, Debug)]
+pub struct FlashMaster(pub u32);
+}
+impl crate::FromBytes for FlashMaster {}
+impl FlashMaster {
+    /// Return whether or not this master can read from `region`.
+    pub fn can_read(&self, version: DescriptorVersion, region: Region) -> bool {
+        let (shift, width) = match version {
+            DescriptorVersion::V1 => (16, 8),
+            DescriptorVersion::V2 => (8, 12),
+        };
+        self.access_bit(shift, width, region)
+    }
+    /// Return whether or not this master can write to `region`.
+    pub fn can_write(&self, version: DescriptorVersion, region: Region) -> bool {
+        let (shift, width) = match version {
+            DescriptorVersion::V1 => (24, 8),
+            DescriptorVersion::V2 => (20, 12),
+        };
+        self.access_bit(shift, width, region)
+    }
+    /// Return the bit for `region` in a field of `width` bits at `shift`.
+    /// Regions past the end of the field have no access.
+    fn access_bit(&self, shift: u32, width: u32, region: Region) -> bool {
+        let region = region as u32;
+        region < width && (self.0 >> (shift + region)) & 1 != 0
+    }
+}
+
+/// The flash descriptor from the start of an SPI flash image.
+#[derive(Debug)]
+pub struct FlashDescriptor {
+    pub map: FlashMap,
+    pub version: DescriptorVersion,
+    /// The first flash component register (FLCOMP).
+    pub components: FlashComponents,
+    /// Region registers (FLREG0, FLREG1, ...)
+    pub regions: Vec<FlashRegion>,
+    /// Master registers (FLMSTR1, FLMSTR2, ...)
+    pub masters: Vec<FlashMaster>,
+    pub pch_straps: Vec<u32>,
+}
+impl FlashDescriptor {
+    /// Offset of the signature in the descriptor.
+    const MAP_OFFSET: usize = 0x10;
+
+    pub fn new(data: &[u8]) -> crate::Result<Self> {
+        let map: FlashMap = crate::read_at(data, Self::MAP_OFFSET)?;
+        let components: FlashComponents = crate::read_at(data, map.fcba())?;
+        let version = if components.read_freq() == 0 {
+            DescriptorVersion::V1
+        } else {
+            DescriptorVersion::V2
+        };
+
+        let regions = crate::read_array_at(data, map.frba(),
+            version.num_regions())?;
+        let masters = crate::read_array_at(data, map.fmba(),
+            version.num_masters())?;
+        let straps = crate::take(data, map.fpsba(), map.pch_strap_len() * 4,
+            "PchStraps")?;
+        let pch_straps = straps.chunks_exact(4).map(u32::read).collect();
+
+        Ok(Self { map, version, components, regions, masters, pch_straps })
+    }
+
+    /// Return the register describing some region (if it exists).
+    pub fn region(&self, region: Region) -> Option<FlashRegion> {
+        self.regions.get(region as usize).copied()
+            .filter(|r| !r.is_empty())
+    }
+
+    /// Return the ME region (if it exists).
+    pub fn me_region(&self) -> Option<FlashRegion> {
+        self.region(Region::Me)
+    }
+
+    /// Return the contents of some region in the flash image `data`.
+    pub fn region_data<'a>(&self, data: &'a [u8], region: Region)
+        -> crate::Result<&'a [u8]>
+    {
+        match self.region(region) {
+            Some(r) => crate::take(data, r.base(), r.len(), "FlashRegion"),
+            None => Err(Error::Invalid {
+                name: "FlashDescriptor", offset: 0,
+                reason: "region isn't present in the descriptor",
+            }),
+        }
+    }
+}
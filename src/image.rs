@@ -0,0 +1,87 @@
+//! Locating the ME firmware in different kinds of input images.
+
+use crate::{
+    Error,
+    ifd::FlashDescriptor,
+    fpt::FlashPartitionTable,
+};
+
+/// The different kinds of images we know how to deal with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    /// A full SPI flash image beginning with a flash descriptor.
+    SpiFlash,
+    /// A bare ME region. Usually, the `$FPT` is preceded by a 16-byte ROM
+    /// bypass vector.
+    MeRegion { rom_bypass: bool },
+    /// An update image (without an FPT) beginning with a `$CPD`.
+    Update,
+}
+
+/// Location of the ME firmware within some input image.
+pub struct MeImage<'a> {
+    pub kind: ImageKind,
+    /// Offset of the ME region in the input image.
+    pub me_offset: usize,
+    /// Contents of the ME region. Partition offsets in the FPT are relative
+    /// to the start of this region.
+    pub me_region: &'a [u8],
+    /// Offset of the `$FPT` header within the ME region (if there is one).
+    pub fpt_offset: Option<usize>,
+}
+impl<'a> MeImage<'a> {
+    /// Length of the ROM bypass vector in front of the `$FPT`.
+    const ROM_BYPASS_LEN: usize = 0x10;
+
+    /// Figure out what kind of image `data` is, and where the ME lives.
+    pub fn new(data: &'a [u8]) -> crate::Result<Self> {
+        if let Ok(ifd) = FlashDescriptor::new(data) {
+            let region = ifd.me_region().ok_or(Error::Invalid {
+                name: "FlashDescriptor", offset: 0,
+                reason: "no ME region in flash image",
+            })?;
+            let me_offset = region.base();
+            let me_region = crate::take(data, me_offset, region.len(),
+                "FlashRegion")?;
+            let fpt_offset = Self::find_fpt(me_region).ok_or(Error::BadMagic {
+                name: "FptHeader", offset: me_offset,
+            })?;
+            return Ok(Self {
+                kind: ImageKind::SpiFlash, me_offset, me_region,
+                fpt_offset: Some(fpt_offset),
+            });
+        }
+
+        if let Some(fpt_offset) = Self::find_fpt(data) {
+            let rom_bypass = fpt_offset == Self::ROM_BYPASS_LEN;
+            return Ok(Self {
+                kind: ImageKind::MeRegion { rom_bypass },
+                me_offset: 0, me_region: data, fpt_offset: Some(fpt_offset),
+            });
+        }
+
+        if data.starts_with(b"$CPD") {
+            return Ok(Self {
+                kind: ImageKind::Update,
+                me_offset: 0, me_region: data, fpt_offset: None,
+            });
+        }
+
+        Err(Error::BadMagic { name: "MeImage", offset: 0 })
+    }
+
+    /// Return the offset of the `$FPT` in some ME region (if it exists).
+    fn find_fpt(me_region: &[u8]) -> Option<usize> {
+        [0, Self::ROM_BYPASS_LEN].iter().copied().find(|off| {
+            me_region.get(*off..*off + 4) == Some(b"$FPT")
+        })
+    }
+
+    /// Parse the flash partition table (if there is one).
+    pub fn fpt(&self) -> Option<crate::Result<FlashPartitionTable>> {
+        self.fpt_offset.map(|off| {
+            FlashPartitionTable::from_bytes(&self.me_region[off..])
+                .map_err(|e| e.shift(self.me_offset + off))
+        })
+    }
+}
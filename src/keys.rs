@@ -0,0 +1,100 @@
+//! Classifying the public keys used to sign manifests.
+//!
+//! Keys are identified by the SHA-256 hash of the modulus and exponent
+//! (see [crate::man::CryptoBlock::public_key_hash]), which is also what
+//! gets fused into a platform.
+//!
+//! This crate doesn't ship with any known hashes. Instead, lists of hashes
+//! can be loaded from text files with one key per line:
+//!
+//! ```text
+//! # <SHA-256 hash> <class> [description]
+//! 0123...cdef  production     Some production key
+//! 4567...89ab  preproduction  Some debug key
+//! ```
+//!
+//! where the class is one of `production`, `preproduction` (or `debug`),
+//! and `oem`.
+
+use std::collections::HashMap;
+use crate::Error;
+
+/// The different kinds of keys used to sign manifests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyClass {
+    /// Intel production key.
+    IntelProduction,
+    /// Intel pre-production (or debug) key.
+    IntelPreProduction,
+    /// Key belonging to some OEM.
+    Oem,
+    /// Some key that isn't in the database.
+    Unknown,
+}
+impl KeyClass {
+    fn parse(x: &str) -> Option<Self> {
+        match x {
+            "production" => Some(Self::IntelProduction),
+            "preproduction" | "debug" => Some(Self::IntelPreProduction),
+            "oem" => Some(Self::Oem),
+            _ => None,
+        }
+    }
+}
+
+/// Information about some known key.
+#[derive(Clone, Debug)]
+pub struct KeyInfo {
+    pub class: KeyClass,
+    pub description: String,
+}
+
+/// A set of known public key hashes.
+#[derive(Clone, Debug, Default)]
+pub struct KeyDatabase {
+    keys: HashMap<[u8; 32], KeyInfo>,
+}
+impl KeyDatabase {
+    pub fn new() -> Self { Self::default() }
+
+    /// Add a key to the database (replacing any existing entry).
+    pub fn insert(&mut self, hash: [u8; 32], class: KeyClass,
+        description: &str)
+    {
+        self.keys.insert(hash, KeyInfo {
+            class, description: description.to_owned()
+        });
+    }
+
+    /// Add all keys listed in `text` to the database.
+    pub fn load(&mut self, text: &str) -> crate::Result<()> {
+        for (offset, line) in crate::text_lines(text) {
+            let mut fields = line.split_whitespace();
+            let hash = fields.next().and_then(|x| {
+                let mut hash = [0u8; 32];
+                hex::decode_to_slice(x, &mut hash).ok().map(|_| hash)
+            });
+            let class = fields.next().and_then(KeyClass::parse);
+            let description = fields.collect::<Vec<_>>().join(" ");
+            match (hash, class) {
+                (Some(hash), Some(class)) =>
+                    self.insert(hash, class, &description),
+                _ => return Err(Error::Invalid {
+                    name: "KeyDatabase", offset,
+                    reason: "expected a SHA-256 hash and a key class",
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return information about some key (if it's in the database).
+    pub fn lookup(&self, hash: &[u8; 32]) -> Option<&KeyInfo> {
+        self.keys.get(hash)
+    }
+
+    /// Return the class of some key.
+    pub fn classify(&self, hash: &[u8; 32]) -> KeyClass {
+        self.lookup(hash).map(|k| k.class).unwrap_or(KeyClass::Unknown)
+    }
+}
@@ -1,23 +1,100 @@
+#[macro_use]
+pub mod bytes;
+pub mod error;
+pub mod checksum;
+pub mod ifd;
+pub mod image;
 pub mod fpt;
 pub mod cpd;
 pub mod man;
+pub mod rsa;
+pub mod keys;
+pub mod bootguard;
 pub mod ext;
 pub mod part;
 pub mod huffman;
 
-/// Trait implemented for types that can be cast from a byte-array.
+pub use error::{ Error, Result };
+
+/// Trait implemented for types that can be decoded from a byte-array.
+///
+/// Types implementing this are usually declared with `le_struct!`, which
+/// describes their little-endian layout.
+pub trait FromBytes: bytes::Field {
+    fn validate(&self) -> Result<()> { Ok(()) }
+    fn from_bytes(x: &[u8]) -> Result<Self> {
+        let x = take(x, 0, Self::SIZE, error::type_name::<Self>())?;
+        let res = Self::read(x);
+        Self::validate(&res)?;
+        Ok(res)
+    }
+}
+
+/// Trait implemented for types that can be encoded into a byte-array.
 ///
-/// NOTE: Types implementing this probably need to be `#[repr(C)]`.
-pub trait FromBytes {
-    fn validate(&self) -> Result<(), &'static str> { Ok(()) }
-    fn from_bytes(x: &[u8]) -> Self where Self: Sized {
-        assert!(x.len() >= std::mem::size_of::<Self>());
-        let res = unsafe { std::ptr::read(x.as_ptr() as *const _) };
-        match Self::validate(&res) {
-            Ok(_) => {},
-            Err(e) => panic!("{:?}", e),
-        }
+/// Encoding a value decoded with [FromBytes] reproduces the original bytes.
+pub trait ToBytes {
+    /// Append the encoding of this value to `out`.
+    fn write_bytes(&self, out: &mut Vec<u8>);
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.write_bytes(&mut res);
         res
     }
 }
+impl<T: bytes::Field> ToBytes for T {
+    fn write_bytes(&self, out: &mut Vec<u8>) { self.write(out) }
+}
+
+/// Return `len` bytes at offset `off` in `x`, or an error describing the
+/// structure `name` as truncated.
+pub(crate) fn take<'a>(x: &'a [u8], off: usize, len: usize, name: &'static str)
+    -> Result<&'a [u8]>
+{
+    match off.checked_add(len) {
+        Some(end) if end <= x.len() => Ok(&x[off..end]),
+        _ => Err(Error::Truncated {
+            name, offset: off, len, avail: x.len().saturating_sub(off),
+        }),
+    }
+}
 
+/// Decode a structure at offset `off` in `x`.
+pub(crate) fn read_at<T: FromBytes>(x: &[u8], off: usize) -> Result<T> {
+    T::from_bytes(x.get(off..).unwrap_or(&[])).map_err(|e| e.shift(off))
+}
+
+/// Decode an array of `count` structures at offset `off` in `x`.
+pub(crate) fn read_array_at<T: FromBytes>(x: &[u8], off: usize, count: usize)
+    -> Result<Vec<T>>
+{
+    read_array(x.get(off..).unwrap_or(&[]), count).map_err(|e| e.shift(off))
+}
+
+/// Decode an array of `count` structures from the start of `x`.
+pub(crate) fn read_array<T: FromBytes>(x: &[u8], count: usize)
+    -> Result<Vec<T>>
+{
+    let len = count.saturating_mul(T::SIZE);
+    take(x, 0, len, error::type_name::<T>())?;
+    let mut res = Vec::with_capacity(count);
+    for idx in 0..count {
+        let off = idx * T::SIZE;
+        res.push(T::from_bytes(&x[off..]).map_err(|e| e.shift(off))?);
+    }
+    Ok(res)
+}
+
+/// Return the non-empty lines of some text file with `#` comments removed,
+/// along with the offset of each line.
+///
+/// Parsers for text files report errors at the offset of the bad line.
+pub(crate) fn text_lines(text: &str) -> impl Iterator<Item=(usize, &str)> {
+    let mut line_off = 0;
+    text.split_inclusive('\n').filter_map(move |line| {
+        let offset = line_off;
+        line_off += line.len();
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() { None } else { Some((offset, line)) }
+    })
+}
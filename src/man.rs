@@ -1,16 +1,87 @@
 
-use std::convert::TryInto;
-use crate::{ cpd, ext };
-use std::io::BufRead;
+use std::fmt;
+use crate::{ ext, Error, rsa };
 
-use crate::FromBytes;
+use crate::{ FromBytes, ToBytes, bytes::Field };
+use sha2::{ Sha256, Digest };
 
-#[repr(transparent)]
+le_struct! {
+/// Build date, stored as binary-coded decimal (i.e. `0x20170522`).
 #[derive(Clone, Copy, Debug)]
 pub struct BCDTimestamp(pub u32);
+}
+impl BCDTimestamp {
+    /// Decode a group of BCD digits (or `None` if a nibble isn't a digit).
+    fn decode(x: u32, digits: u32) -> Option<u32> {
+        (0..digits).rev().try_fold(0, |acc, idx| {
+            let d = (x >> (idx * 4)) & 0xf;
+            if d > 9 { None } else { Some(acc * 10 + d) }
+        })
+    }
+
+    /// Return the date, or `None` if this isn't a valid BCD date.
+    pub fn date(&self) -> Option<ManifestDate> {
+        let year = Self::decode(self.0 >> 16, 4)?;
+        let month = Self::decode((self.0 >> 8) & 0xff, 2)?;
+        let day = Self::decode(self.0 & 0xff, 2)?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        Some(ManifestDate { year: year as u16, month: month as u8, day: day as u8 })
+    }
+}
 
+/// Calendar date decoded from a [BCDTimestamp].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestDate { pub year: u16, pub month: u8, pub day: u8 }
+impl fmt::Display for ManifestDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Version of the firmware described by a manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub hotfix: u16,
+    pub build: u16,
+}
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.major, self.minor, self.hotfix, self.build)
+    }
+}
+
+/// The type of some manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManifestType {
+    /// Manifest describing a code partition.
+    PartitionManifest,
+    Unknown(u32),
+}
+impl From<u32> for ManifestType {
+    fn from(x: u32) -> Self {
+        match x {
+            4 => Self::PartitionManifest,
+            _ => Self::Unknown(x),
+        }
+    }
+}
+
+/// Flags from a manifest header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ManifestFlags(pub u32);
+impl ManifestFlags {
+    /// Set for production (PV) firmware releases.
+    pub fn production_version(&self) -> bool { self.0 & 1 != 0 }
+    /// Set when the manifest is signed with a debug (pre-production) key.
+    pub fn debug_signed(&self) -> bool { self.0 & (1 << 31) != 0 }
+}
+
+le_struct! {
 /// Partition manifest header.
-#[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ManifestHeader {
     manifest_type: u32,
@@ -32,50 +103,165 @@ pub struct ManifestHeader {
     modulus_len_words: u32,
     exponent_size_words: u32,
 }
+}
 impl crate::FromBytes for ManifestHeader {
-    fn validate(&self) -> Result<(), &'static str> {
-        assert_eq!(self.marker, Self::MARKER_MN2);
-        assert_eq!(self.vendor, 0x8086);
-        assert_eq!(self.header_length_words, 0xa1);
+    fn validate(&self) -> crate::Result<()> {
+        if self.marker != Self::MARKER_MN2 {
+            return Err(Error::BadMagic { name: "ManifestHeader", offset: 0 });
+        }
+        if self.vendor != 0x8086 {
+            return Err(Error::Invalid {
+                name: "ManifestHeader", offset: 0,
+                reason: "unexpected vendor ID",
+            });
+        }
+        if self.exponent_size_words != 1 {
+            return Err(Error::Invalid {
+                name: "ManifestHeader", offset: 0,
+                reason: "unexpected exponent size",
+            });
+        }
+        // The header length also covers the crypto block
+        let crypto_len = self.modulus_len() * 2 + self.exponent_len();
+        if self.header_length_words as usize * 4 != Self::SIZE + crypto_len {
+            return Err(Error::Invalid {
+                name: "ManifestHeader", offset: 0,
+                reason: "unexpected header length",
+            });
+        }
         Ok(())
     }
 }
 impl ManifestHeader {
     const MARKER_MN2: [u8; 4] = *b"$MN2";
+
+    /// Return the type of this manifest.
+    pub fn manifest_type(&self) -> ManifestType {
+        ManifestType::from(self.manifest_type)
+    }
+    /// Return the version of the manifest header format.
+    pub fn header_version(&self) -> u32 { self.version }
+    /// Return the flags for this manifest.
+    pub fn flags(&self) -> ManifestFlags { ManifestFlags(self.flags) }
+    /// Return the build date.
+    pub fn date(&self) -> BCDTimestamp { self.date }
+    /// Return the firmware version.
+    pub fn firmware_version(&self) -> FirmwareVersion {
+        FirmwareVersion {
+            major: self.version_major,
+            minor: self.version_minor,
+            hotfix: self.version_hotfix,
+            build: self.version_build,
+        }
+    }
+    /// Return the secure version number (SVN), used for anti-rollback.
+    pub fn secure_version_number(&self) -> u32 { self.secure_version_number }
+    /// Length of the whole manifest in bytes.
+    pub fn manifest_len(&self) -> usize { self.manifest_length_words as usize * 4 }
+
+    /// Length of the RSA modulus (and signature) in bytes.
+    ///
+    /// This is 256 bytes for RSA-2048 (CSME 11), and 384 bytes for RSA-3072
+    /// (CSME 12 and later).
+    pub fn modulus_len(&self) -> usize { self.modulus_len_words as usize * 4 }
+
+    /// Length of the RSA exponent in bytes.
+    pub fn exponent_len(&self) -> usize { self.exponent_size_words as usize * 4 }
 }
 
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
+/// RSA public key and signature for a manifest.
+///
+/// NOTE: The modulus and signature are stored in little-endian order.
+#[derive(Clone, Debug)]
 pub struct CryptoBlock {
-    public_key: [u8; 256],
-    exponent: u32,
-    rsa_signature: [u8; 256],
+    pub public_key: Vec<u8>,
+    pub exponent: u32,
+    pub rsa_signature: Vec<u8>,
+}
+impl CryptoBlock {
+    /// Read a crypto block with the key size described by `header`.
+    pub fn new(x: &[u8], header: &ManifestHeader) -> crate::Result<Self> {
+        let mod_len = header.modulus_len();
+        let crypto = crate::take(x, 0, mod_len * 2 + 4, "CryptoBlock")?;
+        Ok(Self {
+            public_key: crypto[..mod_len].to_vec(),
+            exponent: u32::read(&crypto[mod_len..]),
+            rsa_signature: crypto[mod_len + 4..].to_vec(),
+        })
+    }
+
+    /// Length of this block in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.public_key.len() + 4 + self.rsa_signature.len()
+    }
+
+    /// Return the SHA-256 hash of the public key (the modulus followed by
+    /// the exponent, as they're stored here).
+    pub fn public_key_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.public_key);
+        hasher.update(self.exponent.to_le_bytes());
+        hasher.finalize().into()
+    }
+}
+impl crate::ToBytes for CryptoBlock {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.public_key);
+        self.exponent.write(out);
+        out.extend_from_slice(&self.rsa_signature);
+    }
 }
-impl crate::FromBytes for CryptoBlock {}
 
 
 pub struct CodePartitionManifest {
-    header: ManifestHeader,
-    crypto: CryptoBlock,
-    extensions: Vec<ext::ManifestExtension>,
+    pub header: ManifestHeader,
+    pub crypto: CryptoBlock,
+    pub extensions: Vec<ext::ManifestExtension>,
 }
 impl CodePartitionManifest {
-    pub fn new(x: &[u8]) -> Self {
-        let hdr_len = std::mem::size_of::<ManifestHeader>();
-        let cry_len = std::mem::size_of::<CryptoBlock>();
-        let header = ManifestHeader::from_bytes(&x[0x00..hdr_len]);
-        let crypto = CryptoBlock::from_bytes(&x[hdr_len..hdr_len+cry_len]);
+    pub fn new(x: &[u8]) -> crate::Result<Self> {
+        let hdr_len = ManifestHeader::SIZE;
+        let header = ManifestHeader::from_bytes(x)?;
+        let crypto = CryptoBlock::new(&x[hdr_len..], &header)
+            .map_err(|e| e.shift(hdr_len))?;
         let mut extensions = Vec::new();
 
-        let mut cursor: usize = hdr_len + cry_len;
+        let mut cursor: usize = hdr_len + crypto.len();
         while cursor < x.len() {
             let ext_data = &x[cursor..];
-            let extension = ext::ManifestExtension::new(ext_data);
+            let extension = ext::ManifestExtension::new(ext_data)
+                .map_err(|e| e.shift(cursor))?;
             cursor += extension.hdr.length as usize;
             extensions.push(extension);
         }
-        CodePartitionManifest { header, crypto, extensions }
+        Ok(CodePartitionManifest { header, crypto, extensions })
+    }
+
+    /// Return the data covered by the signature: the header (up to the
+    /// public key) and everything after the crypto block.
+    pub fn signed_data(&self) -> Vec<u8> {
+        let data = self.to_bytes();
+        let body_off = ManifestHeader::SIZE + self.crypto.len();
+        let end = self.header.manifest_len().clamp(body_off, data.len());
+        let mut res = data[..ManifestHeader::SIZE].to_vec();
+        res.extend_from_slice(&data[body_off..end]);
+        res
+    }
+
+    /// Check the RSA signature on this manifest.
+    pub fn verify_signature(&self) -> rsa::SignatureStatus {
+        rsa::verify_pkcs1_sha256(&self.crypto.public_key,
+            self.crypto.exponent, &self.crypto.rsa_signature,
+            &self.signed_data())
+    }
+}
+impl crate::ToBytes for CodePartitionManifest {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        self.header.write(out);
+        self.crypto.write_bytes(out);
+        self.extensions.iter().for_each(|e| e.write_bytes(out));
     }
 }
 
@@ -1,6 +1,8 @@
 
 use std::collections::BTreeMap;
-use crate::{ 
+use sha2::{ Sha256, Digest };
+use crate::{
+    Error,
     cpd::*,
     man::*,
     ext::*,
@@ -19,6 +21,80 @@ pub struct Module {
     pub data: Vec<u8>,
     /// Original contents of the module file.
     pub raw_data: Vec<u8>,
+    /// Byte order of the digest in the module attributes (if it's known
+    /// for this generation).
+    pub digest_order: Option<DigestOrder>,
+}
+impl Module {
+    /// Return the data covered by the digest in the module attributes.
+    ///
+    /// NOTE: For LZMA-compressed modules, the digest covers the compressed
+    /// file. For Huffman-compressed modules, it covers the decompressed data.
+    pub fn digest_data(&self) -> crate::Result<&[u8]> {
+        Ok(match self.attr.compression_type()? {
+            CompressionType::None | CompressionType::Huff => &self.data,
+            CompressionType::Lzma => &self.raw_data,
+        })
+    }
+
+    /// Check the SHA-256 digest of this module against the digest in the
+    /// module attributes.
+    ///
+    /// The computed digest in the result is in the same byte order as the
+    /// stored digest.
+    pub fn verify_digest(&self) -> crate::Result<DigestStatus> {
+        let order = match self.digest_order {
+            Some(order) => order,
+            None => return Ok(DigestStatus::Unsupported),
+        };
+        let expected = self.attr.sha256_digest;
+        let computed = order.apply(Sha256::digest(self.digest_data()?).into());
+        if expected == computed {
+            Ok(DigestStatus::Valid)
+        } else {
+            Ok(DigestStatus::Invalid { expected, computed })
+        }
+    }
+}
+
+/// Byte order of the SHA-256 digests stored in manifests and metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestOrder {
+    /// Digests are stored in the order they're computed.
+    Normal,
+    /// Digests are stored in reversed byte order.
+    Reversed,
+}
+impl DigestOrder {
+    /// Return the byte order used by a partition with some directory.
+    ///
+    /// NOTE: CSME 11 (with version 1 directories) stores digests reversed.
+    /// We don't know the order used by later generations, so this returns
+    /// `None` for them.
+    pub fn for_cpd(cpd: &CodePartitionDirectory) -> Option<Self> {
+        match cpd.header.version() {
+            CpdHeaderVersion::V1 => Some(Self::Reversed),
+            CpdHeaderVersion::V2 => None,
+        }
+    }
+
+    /// Return a computed digest in the order it would be stored.
+    pub fn apply(&self, digest: [u8; 32]) -> [u8; 32] {
+        let mut res = digest;
+        if *self == Self::Reversed {
+            res.reverse();
+        }
+        res
+    }
+}
+
+/// Result of checking the digest of some module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestStatus {
+    Valid,
+    Invalid { expected: [u8; 32], computed: [u8; 32] },
+    /// The byte order of digests isn't known for this generation.
+    Unsupported,
 }
 
 /// Representing a code partition (containing CSME modules).
@@ -30,39 +106,77 @@ pub struct CodePartition {
     /// Map from module names to copies of module data (and metadata)
     pub modules: BTreeMap<String, Module>,
     /// Copy of the raw data for this partition
-    _raw_data: Vec<u8>,
+    raw_data: Vec<u8>,
+}
+
+/// Some disagreement between the `PartitionInfo` extension in a manifest
+/// and the partition it describes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionInfoMismatch {
+    /// The partition is shorter than the length in the manifest.
+    Length { expected: usize, actual: usize },
+    /// The digest of the partition doesn't match the manifest.
+    Digest { expected: [u8; 32], computed: [u8; 32] },
+    /// The name in the manifest doesn't match the name in the directory.
+    CpdName { expected: [u8; 4], actual: [u8; 4] },
+    /// The name in the manifest doesn't match the name in the FPT.
+    FptName { expected: [u8; 4], actual: [u8; 4] },
+}
+
+/// Some disagreement between the list of modules in a manifest and the
+/// module metadata (`.met`) files in the partition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataMismatch {
+    /// A module in the manifest has no metadata file.
+    Missing { name: String },
+    /// A metadata file isn't listed in the manifest.
+    Extra { name: String },
+    /// The size of a metadata file doesn't match the manifest.
+    Size { name: String, expected: usize, actual: usize },
+    /// The digest of a metadata file doesn't match the manifest.
+    Digest { name: String, expected: [u8; 32], computed: [u8; 32] },
 }
 impl CodePartition {
-    pub fn new(data: &[u8]) -> Self {
+    pub fn new(data: &[u8]) -> crate::Result<Self> {
         let mut modules: BTreeMap<String, Module> = BTreeMap::new();
         let part_data = data.to_vec();
 
-        let cpd = CodePartitionDirectory::new(&part_data);
+        let cpd = CodePartitionDirectory::new(&part_data)?;
+        let digest_order = DigestOrder::for_cpd(&cpd);
 
         // NOTE: The manifest is typically the first entry in the directory.
-        let man = if cpd.entries[0].filename().ends_with(".man") {
-            let off = cpd.entries[0].attrs.address() as usize;
-            let len = cpd.entries[0].length as usize;
-            CodePartitionManifest::new(&part_data[off..off+len])
-        } else {
-            panic!("No manifest for code partition");
+        let man = match cpd.entries.first() {
+            Some(e) if e.filename().ends_with(".man") => {
+                let man_data = crate::take(&part_data, e.offset(), e.len(),
+                    "CodePartitionManifest")?;
+                CodePartitionManifest::new(man_data)
+                    .map_err(|err| err.shift(e.offset()))?
+            },
+            _ => return Err(Error::Invalid {
+                name: "CodePartitionDirectory", offset: 0,
+                reason: "no manifest for code partition",
+            }),
         };
 
         // Use the metadata files in this partition to make a map of modules
         for e in cpd.entries.iter().filter(|x| x.filename().ends_with(".met")) {
-            assert_eq!(e.attrs.compress_flag(), false);
-            let met_data = &part_data[e.offset()..e.offset() + e.len()];
-            let module_name = std::str::from_utf8(&e.name)
-                .expect("Couldn't interpret CPD entry name as UTF-8")
-                .trim_end_matches(char::from(0)).trim_end_matches(".met")
-                .to_owned();
+            if e.attrs.compress_flag() {
+                return Err(Error::Invalid {
+                    name: "CpdEntry", offset: e.offset(),
+                    reason: "metadata file is compressed",
+                });
+            }
+            let met_data = crate::take(&part_data, e.offset(), e.len(),
+                "ModuleMetadata")?;
+            let module_name = e.filename().trim_end_matches(".met").to_owned();
             let mut module_attr = None;
             let extensions: Vec<ManifestExtension> = {
                 let mut cur: usize = 0;
                 let mut res = Vec::new();
                 while cur < met_data.len() {
                     let ext_data = &met_data[cur..];
-                    let ext = ManifestExtension::new(ext_data);
+                    let ext = ManifestExtension::new(ext_data)
+                        .map_err(|err| err.shift(e.offset() + cur))?;
                     if let ExtensionData::ModuleAttrs { data } = ext.data {
                         module_attr = Some(data);
                     }
@@ -71,48 +185,197 @@ impl CodePartition {
                 }
                 res
             };
-            modules.insert(module_name.clone(), 
-                Module { 
+            let attr = module_attr.ok_or(Error::Invalid {
+                name: "ModuleMetadata", offset: e.offset(),
+                reason: "no module attributes extension",
+            })?;
+            modules.insert(module_name.clone(),
+                Module {
                     name: module_name,
-                    attr: module_attr.unwrap(),
-                    ext: extensions, 
+                    attr,
+                    ext: extensions,
                     data: Vec::new(),
                     raw_data: Vec::new(),
+                    digest_order,
                 }
             );
         }
 
         // Decompress the contents of each module
         for (name, module) in &mut modules {
-            let raw_data: Vec<&[u8]> = cpd.entries.iter().filter_map(|x| {
-                if &x.filename() == name {
-                    Some(&part_data[x.offset()..x.offset() + x.len()])
-                } else {
-                    None
-                }
-            }).collect();
-            assert_eq!(raw_data.len(), 1);
-            let mod_data: Vec<u8> = match module.attr.compression_type() {
-                CompressionType::None => raw_data[0].to_vec(),
+            let mut files = cpd.entries.iter()
+                .filter(|x| x.filename() == name);
+            let (entry, raw_data) = match (files.next(), files.next()) {
+                (Some(e), None) => (e, crate::take(&part_data, e.offset(),
+                    e.len(), "Module")?),
+                _ => return Err(Error::Invalid {
+                    name: "CodePartitionDirectory", offset: 0,
+                    reason: "expected exactly one file for each module",
+                }),
+            };
+            let mod_data: Vec<u8> = match module.attr.compression_type()
+                .map_err(|err| err.shift(entry.offset()))?
+            {
+                CompressionType::None => raw_data.to_vec(),
                 CompressionType::Lzma => {
+                    // The LZMA header has three extra bytes before the data
+                    let hdr = crate::take(raw_data, 0, 0x11, "LzmaHeader")
+                        .map_err(|err| err.shift(entry.offset()))?;
                     let mut buf = Vec::new();
-                    buf.extend_from_slice(&raw_data[0][..0xe]);
-                    buf.extend_from_slice(&raw_data[0][0x11..]);
-                    match lzma::decompress(&buf) {
-                        Ok(res) => res,
-                        Err(e) => panic!("{}", e),
-                    }
+                    buf.extend_from_slice(&hdr[..0xe]);
+                    buf.extend_from_slice(&raw_data[0x11..]);
+                    lzma::decompress(&buf).map_err(|err| Error::Decompression {
+                        name: "Module", offset: entry.offset(),
+                        reason: err.to_string(),
+                    })?
                 },
-                CompressionType::Huff => 
-                    decompress_huff(&raw_data[0], &module.attr),
+                CompressionType::Huff =>
+                    decompress_huff(raw_data, &module.attr)
+                        .map_err(|err| err.shift(entry.offset()))?,
             };
-            assert_eq!(mod_data.len(), module.attr.uncompressed_size());
+            if mod_data.len() != module.attr.uncompressed_size() {
+                return Err(Error::Decompression {
+                    name: "Module", offset: entry.offset(),
+                    reason: format!("expected {:#x} bytes, got {:#x}",
+                        module.attr.uncompressed_size(), mod_data.len()),
+                });
+            }
             module.data = mod_data;
-            module.raw_data = raw_data[0].to_vec();
+            module.raw_data = raw_data.to_vec();
         }
 
-        Self { cpd, man, modules, _raw_data: part_data }
+        Ok(Self { cpd, man, modules, raw_data: part_data })
+    }
+
+    /// Return the byte order of the digests in this partition (if it's
+    /// known for this generation).
+    pub fn digest_order(&self) -> Option<DigestOrder> {
+        DigestOrder::for_cpd(&self.cpd)
     }
-}
 
+    /// Return the `PartitionInfo` extension from the manifest (if it exists).
+    pub fn partition_info(&self) -> Option<&ManifestPartitionInfoExt> {
+        self.man.extensions.iter().find_map(|ext| match &ext.data {
+            ExtensionData::PartitionInfo { data, .. } => Some(data),
+            _ => None,
+        })
+    }
+
+    /// Return the list of modules from the manifest.
+    ///
+    /// NOTE: CSME 11 lists modules in the `PartitionInfo` extension, and
+    /// later versions list them in the `SignedPackageInfo` extension.
+    pub fn module_info(&self) -> &[ManifestModuleInfoExt] {
+        self.man.extensions.iter().find_map(|ext| match &ext.data {
+            ExtensionData::PartitionInfo { entries, .. }
+            | ExtensionData::SignedPackageInfo { entries, .. }
+                if !entries.is_empty() => Some(&entries[..]),
+            _ => None,
+        }).unwrap_or(&[])
+    }
+
+    /// Check each module metadata (`.met`) file in the directory against
+    /// the list of modules in the manifest, returning a list of any
+    /// mismatches.
+    ///
+    /// NOTE: Digests are only compared when their byte order is known (see
+    /// [DigestOrder::for_cpd]).
+    pub fn verify_metadata(&self) -> Vec<MetadataMismatch> {
+        let order = self.digest_order();
+        let mut res = Vec::new();
+        let mut met_files: BTreeMap<&str, &CpdEntry> = self.cpd.entries.iter()
+            .filter_map(|e| {
+                e.filename().strip_suffix(".met").map(|name| (name, e))
+            }).collect();
+
+        for info in self.module_info() {
+            let name = info.name();
+            let entry = match met_files.remove(name.as_str()) {
+                Some(e) => e,
+                None => {
+                    res.push(MetadataMismatch::Missing { name });
+                    continue;
+                },
+            };
+            // Files were already checked against the partition in new()
+            let data = &self.raw_data[entry.offset()..][..entry.len()];
+            if data.len() != info.metadata_size() {
+                res.push(MetadataMismatch::Size {
+                    name: name.clone(),
+                    expected: info.metadata_size(), actual: data.len(),
+                });
+            }
+            if let Some(order) = order {
+                let expected = info.metadata_digest();
+                let computed = order.apply(Sha256::digest(data).into());
+                if expected != computed {
+                    res.push(MetadataMismatch::Digest {
+                        name, expected, computed
+                    });
+                }
+            }
+        }
+        res.extend(met_files.keys().map(|name| MetadataMismatch::Extra {
+            name: name.to_string(),
+        }));
+        res
+    }
+
+    /// Check this partition against the `PartitionInfo` extension in the
+    /// manifest, returning a list of any mismatches.
+    ///
+    /// If `fpt_name` is given, the name in the manifest is also compared to
+    /// the name of the FPT entry for this partition.
+    ///
+    /// NOTE: The digest covers the first `part_len` bytes of the partition,
+    /// except for the manifest itself. Some partitions leave the digest
+    /// zeroed, in which case it's ignored. The digest is also ignored when
+    /// its byte order isn't known (see [DigestOrder::for_cpd]).
+    pub fn verify_partition_info(&self, fpt_name: Option<[u8; 4]>)
+        -> crate::Result<Vec<PartitionInfoMismatch>>
+    {
+        let info = self.partition_info().ok_or(Error::Invalid {
+            name: "CodePartitionManifest", offset: 0,
+            reason: "no partition info extension",
+        })?;
+        let mut res = Vec::new();
+
+        let expected = info.part_name();
+        let actual = self.cpd.header.partition_name;
+        if expected != actual {
+            res.push(PartitionInfoMismatch::CpdName { expected, actual });
+        }
+        if let Some(actual) = fpt_name {
+            if expected != actual {
+                res.push(PartitionInfoMismatch::FptName { expected, actual });
+            }
+        }
+
+        let data = match self.raw_data.get(..info.part_len()) {
+            Some(data) => data,
+            None => {
+                res.push(PartitionInfoMismatch::Length {
+                    expected: info.part_len(), actual: self.raw_data.len(),
+                });
+                return Ok(res);
+            },
+        };
+        if let Some(order) = self.digest_order() {
+            // The manifest was checked against the partition in new()
+            let man = &self.cpd.entries[0];
+            let man_start = man.offset().min(data.len());
+            let man_end = (man.offset() + man.len()).min(data.len());
+            let mut hasher = Sha256::new();
+            hasher.update(&data[..man_start]);
+            hasher.update(&data[man_end..]);
+
+            let expected = info.part_digest();
+            let computed = order.apply(hasher.finalize().into());
+            if expected != [0; 32] && expected != computed {
+                res.push(PartitionInfoMismatch::Digest { expected, computed });
+            }
+        }
+        Ok(res)
+    }
+}
 
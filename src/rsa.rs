@@ -0,0 +1,68 @@
+//! RSA signature verification.
+
+use num_bigint::BigUint;
+use sha2::{ Sha256, Digest };
+
+/// Result of checking some RSA signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Valid,
+    Invalid,
+    /// We don't know how to deal with keys of this size (in bits).
+    UnsupportedKeySize(usize),
+    /// The signature uses some scheme other than PKCS#1 v1.5 with SHA-256.
+    UnsupportedScheme,
+}
+
+/// Modulus sizes (in bytes) that we expect to find in signatures.
+const SUPPORTED_KEY_SIZES: [usize; 2] = [256, 384];
+
+/// DER encoding of the `DigestInfo` prefix for SHA-256 (see RFC 8017).
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+    0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+];
+
+/// Check an RSASSA-PKCS1-v1_5 signature (with SHA-256) over `data`.
+///
+/// The modulus and signature are little-endian, which is how they're
+/// stored in manifests.
+pub fn verify_pkcs1_sha256(modulus: &[u8], exponent: u32, signature: &[u8],
+    data: &[u8]) -> SignatureStatus
+{
+    let key_len = modulus.len();
+    if !SUPPORTED_KEY_SIZES.contains(&key_len) {
+        return SignatureStatus::UnsupportedKeySize(key_len * 8);
+    }
+    if signature.len() != key_len {
+        return SignatureStatus::Invalid;
+    }
+
+    let n = BigUint::from_bytes_le(modulus);
+    let s = BigUint::from_bytes_le(signature);
+    if s >= n {
+        return SignatureStatus::Invalid;
+    }
+    let m = s.modpow(&BigUint::from(exponent), &n).to_bytes_be();
+    if m.len() > key_len {
+        return SignatureStatus::Invalid;
+    }
+
+    // Build the expected encoded message and compare it to the result
+    let mut expected = vec![0xff; key_len];
+    expected[0] = 0x00;
+    expected[1] = 0x01;
+    let digest_off = key_len - Sha256::output_size();
+    let info_off = digest_off - SHA256_DIGEST_INFO.len();
+    expected[info_off - 1] = 0x00;
+    expected[info_off..digest_off].copy_from_slice(&SHA256_DIGEST_INFO);
+    expected[digest_off..].copy_from_slice(&Sha256::digest(data));
+
+    let mut actual = vec![0; key_len - m.len()];
+    actual.extend_from_slice(&m);
+    if actual == expected {
+        SignatureStatus::Valid
+    } else {
+        SignatureStatus::Invalid
+    }
+}
@@ -0,0 +1,159 @@
+//! Check Boot Guard key manifests and boot policy manifests.
+//!
+//! The manifests in `tests/data` are signed with throwaway keys. The key
+//! manifest (`keym.bin`) trusts the key used to sign the boot policy
+//! manifest (`acbp.bin`).
+
+use csme_rs::{ bootguard::*, rsa::SignatureStatus };
+
+const KEYM: &[u8] = include_bytes!("data/keym.bin");
+const ACBP: &[u8] = include_bytes!("data/acbp.bin");
+
+/// Hashes of the keys used to sign each manifest.
+const KEYM_KEY: &str =
+    "56b5f21dd5b602ed4b93d6f0a959b824e7a26cd0e70746aadd9ad1298738685f";
+const ACBP_KEY: &str =
+    "d4eac31369a4323a5d4786bd9c0e666e03fc4e64c2632fcd339ae45df32a8bb6";
+
+/// Offsets of the key signatures in each manifest.
+const KEYM_SIG: usize = 0x80;
+const ACBP_SIG: usize = 0x50;
+
+#[test]
+fn parse_key_manifest() {
+    let km = KeyManifest::new(KEYM).unwrap();
+    assert_eq!(km.header.key_signature_offset as usize, KEYM_SIG);
+    assert_eq!((km.header.km_version, km.header.km_svn, km.header.km_id),
+        (1, 2, 3));
+    assert_eq!(km.keys.len(), 2);
+
+    let key = &km.keys[0];
+    assert_eq!(key.usage, KeyUsage(1));
+    assert_eq!(key.algorithm, HashAlgorithm::Sha256);
+    assert_eq!(hex::encode(&key.digest), ACBP_KEY);
+    let key = &km.keys[1];
+    assert_eq!(key.usage, KeyUsage(4));
+    assert_eq!(key.algorithm, HashAlgorithm::Sha384);
+    assert_eq!(key.digest.len(), 48);
+
+    let keys: Vec<&KeyHash> = km.boot_policy_keys().collect();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(km.verify_signature(), SignatureStatus::Valid);
+}
+
+#[test]
+fn parse_boot_policy_manifest() {
+    let bpm = BootPolicyManifest::new(ACBP).unwrap();
+    assert_eq!(bpm.header.key_signature_offset as usize, ACBP_SIG);
+    assert_eq!((bpm.header.bpm_revision, bpm.header.bpm_svn,
+        bpm.header.acm_svn, bpm.header.nem_pages), (1, 5, 6, 3));
+
+    // The signature element isn't included
+    let markers: Vec<&[u8]> = bpm.elements.iter().map(|e| &e.hdr.marker[..])
+        .collect();
+    assert_eq!(markers, [b"__IBBS__", b"__TXTS__"]);
+    assert_eq!(bpm.elements[0].data, (0..20).collect::<Vec<u8>>());
+    assert_eq!(bpm.elements[1].data, b"TXT!");
+    assert_eq!(bpm.verify_signature(), SignatureStatus::Valid);
+}
+
+#[test]
+fn parse_key_signatures() {
+    let manifests = [(KEYM, KEYM_SIG, KEYM_KEY), (ACBP, ACBP_SIG, ACBP_KEY)];
+    for (data, sig_off, key_hash) in manifests.iter() {
+        let sig = KeySignature::new(&data[*sig_off..]).unwrap();
+        assert_eq!(sig.version, 0x10);
+        assert_eq!(sig.exponent, 65537);
+        assert_eq!(sig.modulus, &data[sig_off + 10..][..256]);
+        assert_eq!(sig.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(sig.signature, &data[sig_off + 10 + 256 + 7..][..256]);
+        assert_eq!(sig.signature.len(), data.len() - sig_off - 273);
+        assert_eq!(hex::encode(sig.public_key_hash()), *key_hash);
+    }
+
+    // Only RSASSA with SHA-256 is supported
+    let mut data = KEYM.to_vec();
+    data[KEYM_SIG + 10 + 256 + 5] = 0x0c;
+    let km = KeyManifest::new(&data).unwrap();
+    assert_eq!(km.signature.hash_algorithm, HashAlgorithm::Sha384);
+    assert_eq!(km.verify_signature(), SignatureStatus::UnsupportedScheme);
+
+    // Only RSA keys are supported
+    let mut data = KEYM.to_vec();
+    data[KEYM_SIG + 1] = 0x23;
+    let err = KeyManifest::new(&data).err().unwrap();
+    assert_eq!((err.name(), err.offset()), ("KeySignature", KEYM_SIG + 1));
+}
+
+#[test]
+fn decode_key_usage() {
+    let usage = KeyUsage(0x0f);
+    assert!(usage.boot_policy() && usage.fit_patch());
+    assert!(usage.acm() && usage.sdev());
+    let usage = KeyUsage(0x05);
+    assert!(usage.boot_policy() && !usage.fit_patch());
+    assert!(usage.acm() && !usage.sdev());
+    assert_eq!(format!("{:?}", usage), "KeyUsage(0x5 BootPolicy Acm)");
+    assert_eq!(format!("{:?}", KeyUsage(0x10)), "KeyUsage(0x10)");
+}
+
+#[test]
+fn boot_policy_key_is_trusted() {
+    let km = KeyManifest::new(KEYM).unwrap();
+    let bpm = BootPolicyManifest::new(ACBP).unwrap();
+    assert!(bpm.is_trusted_by(&km));
+
+    // A different key hash
+    let mut data = KEYM.to_vec();
+    data[0x18 + 12] ^= 1;
+    let km = KeyManifest::new(&data).unwrap();
+    assert_eq!(km.verify_signature(), SignatureStatus::Invalid);
+    assert!(!bpm.is_trusted_by(&km));
+
+    // The right hash, but the key can't sign boot policy manifests
+    let mut data = KEYM.to_vec();
+    data[0x18] = 0x02;
+    let km = KeyManifest::new(&data).unwrap();
+    assert!(!bpm.is_trusted_by(&km));
+}
+
+#[test]
+fn reject_tampered_boot_guard_manifests() {
+    for off in [0x11, KEYM_SIG - 1, KEYM.len() - 1].iter() {
+        let mut data = KEYM.to_vec();
+        data[*off] ^= 1;
+        let km = KeyManifest::new(&data).unwrap();
+        assert_eq!(km.verify_signature(), SignatureStatus::Invalid,
+            "offset {:#x}", off);
+    }
+    for off in [0x0f, ACBP_SIG - 1, ACBP.len() - 1].iter() {
+        let mut data = ACBP.to_vec();
+        data[*off] ^= 1;
+        let bpm = BootPolicyManifest::new(&data).unwrap();
+        assert_eq!(bpm.verify_signature(), SignatureStatus::Invalid,
+            "offset {:#x}", off);
+    }
+}
+
+#[test]
+fn reject_bad_boot_guard_headers() {
+    // Unsupported structure versions
+    let mut data = KEYM.to_vec();
+    data[8] = 0x10;
+    assert!(KeyManifest::new(&data).is_err());
+    let mut data = ACBP.to_vec();
+    data[8] = 0x10;
+    assert!(BootPolicyManifest::new(&data).is_err());
+
+    // The key signature can't overlap the key hashes
+    let mut data = KEYM.to_vec();
+    data[0x0c] = 0x40;
+    let err = KeyManifest::new(&data).err().unwrap();
+    assert_eq!(err.name(), "KeyManifestHeader");
+
+    // Elements must be at least as large as their header
+    let mut data = ACBP.to_vec();
+    data[0x14 + 10] = 4;
+    let err = BootPolicyManifest::new(&data).err().unwrap();
+    assert_eq!((err.name(), err.offset()), ("BootPolicyElementHeader", 0x14));
+}
@@ -0,0 +1,132 @@
+//! Check the checksums over partition tables and directories.
+
+use csme_rs::{ ToBytes, bytes::Field, checksum::*, fpt::*, cpd::* };
+
+/// Build an FPT header with some header version.
+fn fpt_header(version: u8, num_entries: u32, checksum: u8, crc32: u32)
+    -> Vec<u8>
+{
+    let mut res = b"$FPT".to_vec();
+    res.extend_from_slice(&num_entries.to_le_bytes());
+    res.extend_from_slice(&[version, 0x10, 0x20, checksum]);
+    res.extend_from_slice(&1u16.to_le_bytes());
+    res.extend_from_slice(&2u16.to_le_bytes());
+    res.extend_from_slice(&0u32.to_le_bytes());
+    res.extend_from_slice(&crc32.to_le_bytes());
+    for x in [15u16, 0, 20, 1234].iter() {
+        res.extend_from_slice(&x.to_le_bytes());
+    }
+    res
+}
+
+/// Build a partition table with two entries (`FTPR` and `NFTP`).
+fn fpt_bytes(version: u8, checksum: u8, crc32: u32) -> Vec<u8> {
+    let mut res = fpt_header(version, 2, checksum, crc32);
+    let entries = [(b"FTPR", 0x1000u32, 0x2000u32, 0x81u32),
+        (b"NFTP", 0x3000, 0x4000, 0x82)];
+    for (name, offset, length, attrs) in entries.iter() {
+        res.extend_from_slice(*name);
+        for x in [0, *offset, *length, 0, 0, 0, *attrs].iter() {
+            res.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+    res
+}
+
+#[test]
+fn fpt_sum8_checksum() {
+    let fpt = FlashPartitionTable::from_bytes(&fpt_header(0x20, 0, 0xa6, 0))
+        .unwrap();
+    assert_eq!(fpt.checksum(), Checksum::Sum8(0xa6));
+    assert!(fpt.verify_checksum());
+    assert_eq!(fpt.header.flash_layout(), Some(FlashLayout::Spi));
+    assert_eq!(fpt.header.flags(), None);
+
+    // The checksum covers the entries too
+    let data = fpt_bytes(0x20, 0x8d, 0);
+    let fpt = FlashPartitionTable::from_bytes(&data).unwrap();
+    assert_eq!(fpt.entries.len(), 2);
+    assert!(fpt.verify_checksum());
+
+    let mut bad = data.clone();
+    bad[FptHeader::SIZE + FptEntry::SIZE + 8] ^= 1;
+    let mut fpt = FlashPartitionTable::from_bytes(&bad).unwrap();
+    assert!(!fpt.verify_checksum());
+    fpt.update_checksum();
+    assert!(fpt.verify_checksum());
+
+    let mut fpt = FlashPartitionTable::from_bytes(&fpt_bytes(0x20, 0x12, 0))
+        .unwrap();
+    assert!(!fpt.verify_checksum());
+    fpt.update_checksum();
+    assert_eq!(fpt.checksum(), Checksum::Sum8(0x8d));
+    assert_eq!(fpt.to_bytes(), data);
+}
+
+#[test]
+fn fpt_crc32_checksum() {
+    let data = fpt_header(0x21, 0, 0, 0x90d5_adce);
+    let fpt = FlashPartitionTable::from_bytes(&data).unwrap();
+    assert_eq!(fpt.checksum(), Checksum::Crc32(0x90d5_adce));
+    assert!(fpt.verify_checksum());
+    // The CRC32 isn't a layout
+    assert_eq!(fpt.header.flash_layout(), None);
+    assert_eq!(fpt.header.flags(), Some(FptFlags(0)));
+
+    let mut fpt = FlashPartitionTable::from_bytes(&fpt_header(0x21, 0, 0, 1))
+        .unwrap();
+    assert!(!fpt.verify_checksum());
+    fpt.update_checksum();
+    assert!(fpt.verify_checksum());
+    assert_eq!(fpt.to_bytes(), data);
+
+    // Only the header is covered by the CRC32
+    let mut data = fpt_bytes(0x21, 0, 0x93be_57f4);
+    let fpt = FlashPartitionTable::from_bytes(&data).unwrap();
+    assert!(fpt.verify_checksum());
+    data[FptHeader::SIZE + 8] ^= 1;
+    let fpt = FlashPartitionTable::from_bytes(&data).unwrap();
+    assert!(fpt.verify_checksum());
+}
+
+/// Build a directory with a single entry.
+fn cpd_bytes(version: u8, checksum: u8, crc32: u32) -> Vec<u8> {
+    let mut res = b"$CPD".to_vec();
+    res.extend_from_slice(&1u32.to_le_bytes());
+    let header_len = if version == 2 { 0x14 } else { 0x10 };
+    res.extend_from_slice(&[version, 1, header_len, checksum]);
+    res.extend_from_slice(b"FTPR");
+    if version == 2 {
+        res.extend_from_slice(&crc32.to_le_bytes());
+    }
+    res.extend_from_slice(b"FTPR.man\0\0\0\0");
+    for x in [0x30u32, 0x100, 0].iter() {
+        res.extend_from_slice(&x.to_le_bytes());
+    }
+    res
+}
+
+#[test]
+fn cpd_sum8_checksum() {
+    let cpd = CodePartitionDirectory::new(&cpd_bytes(1, 0xdf, 0)).unwrap();
+    assert_eq!(cpd.checksum(), Checksum::Sum8(0xdf));
+    assert!(cpd.verify_checksum());
+
+    let mut cpd = CodePartitionDirectory::new(&cpd_bytes(1, 0, 0)).unwrap();
+    assert!(!cpd.verify_checksum());
+    cpd.update_checksum();
+    assert_eq!(cpd.checksum(), Checksum::Sum8(0xdf));
+}
+
+#[test]
+fn cpd_crc32_checksum() {
+    let data = cpd_bytes(2, 0, 0x7855_5fad);
+    let cpd = CodePartitionDirectory::new(&data).unwrap();
+    assert_eq!(cpd.checksum(), Checksum::Crc32(0x7855_5fad));
+    assert!(cpd.verify_checksum());
+
+    let mut cpd = CodePartitionDirectory::new(&cpd_bytes(2, 0, 0)).unwrap();
+    assert!(!cpd.verify_checksum());
+    cpd.update_checksum();
+    assert_eq!(cpd.to_bytes(), data);
+}
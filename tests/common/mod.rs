@@ -0,0 +1,39 @@
+//! Helpers shared by the tests and benchmarks.
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use csme_rs::ext::*;
+
+/// Return some data resembling the contents of a module: a mix of code-like
+/// bytes, padding, and strings.
+pub fn module_data(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    let mut res = Vec::with_capacity(len);
+    while res.len() < len {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        match (state >> 16) % 4 {
+            0 => res.extend_from_slice(&[0; 0x40]),
+            1 => res.extend_from_slice(&[0xff; 0x10]),
+            2 => res.extend_from_slice(b"\x55\x89\xe5\x83\xec\x08CSME"),
+            _ => res.extend((0..0x20).map(|i| (state >> (i % 24)) as u8)),
+        }
+    }
+    res.truncate(len);
+    res
+}
+
+/// Return module attributes for Intel module data of the given sizes.
+pub fn module_attrs(compression_type: CompressionType,
+    uncompressed_size: usize, compressed_size: usize,
+    sha256_digest: [u8; 32]) -> ModAttrExt
+{
+    ModAttrExt {
+        compression_type: compression_type as u8,
+        reserved0: 0, reserved1: 0, reserved2: 0,
+        uncompressed_size: uncompressed_size as u32,
+        compressed_size: compressed_size as u32,
+        ven_module_id: 0, ven_id: 0x8086,
+        sha256_digest,
+    }
+}
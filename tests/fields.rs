@@ -0,0 +1,65 @@
+//! Check the decoding of bitfields and other values in headers.
+
+use csme_rs::{ FromBytes, fpt::*, man::* };
+
+const MAN_2048: &[u8] = include_bytes!("data/man2048.bin");
+
+#[test]
+fn decode_fpt_entry_attributes() {
+    // Type 0x45, reserved bits 8 and 17, both block write locks, invalid
+    let attrs = FptEntryAttributes(0xff03_8145);
+    // Bit 6 is part of the type
+    assert_eq!(attrs.kind(), PartitionType::Unknown(0x45));
+    assert_eq!(attrs.reserved0(), 0x02);
+    assert!(attrs.bwl0());
+    assert!(attrs.bwl1());
+    assert_eq!(attrs.reserved1(), 0x01);
+    assert_eq!(attrs.valid_byte(), 0xff);
+    assert!(!attrs.entry_valid());
+
+    let attrs = FptEntryAttributes(0x0000_0002);
+    assert_eq!(attrs.kind(), PartitionType::Nvram);
+    assert!(!attrs.bwl0() && !attrs.bwl1());
+    assert!(attrs.entry_valid());
+
+    let kinds: Vec<PartitionType> = (0..7).map(PartitionType::from).collect();
+    assert_eq!(kinds, [PartitionType::Code, PartitionType::Data,
+        PartitionType::Nvram, PartitionType::Generic, PartitionType::Effs,
+        PartitionType::Rom, PartitionType::Unknown(6)]);
+}
+
+#[test]
+fn decode_manifest_dates() {
+    let date = BCDTimestamp(0x2017_0522).date().unwrap();
+    assert_eq!(date, ManifestDate { year: 2017, month: 5, day: 22 });
+    assert_eq!(date.to_string(), "2017-05-22");
+
+    // Nibbles that aren't decimal digits
+    assert_eq!(BCDTimestamp(0x2017_052a).date(), None);
+    assert_eq!(BCDTimestamp(0x20a7_0522).date(), None);
+    // Digits outside of the range for a month or day
+    assert_eq!(BCDTimestamp(0x2017_1322).date(), None);
+    assert_eq!(BCDTimestamp(0x2017_0500).date(), None);
+}
+
+#[test]
+fn decode_manifest_flags() {
+    let flags = ManifestFlags(0x0000_0001);
+    assert!(flags.production_version());
+    assert!(!flags.debug_signed());
+    let flags = ManifestFlags(0x8000_0000);
+    assert!(!flags.production_version());
+    assert!(flags.debug_signed());
+
+    let mut data = MAN_2048.to_vec();
+    let header = ManifestHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.manifest_type(), ManifestType::PartitionManifest);
+    assert_eq!(header.flags(), ManifestFlags(0));
+
+    data[0x00] = 5;
+    data[0x0c..0x10].copy_from_slice(&0x8000_0001u32.to_le_bytes());
+    let header = ManifestHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.manifest_type(), ManifestType::Unknown(5));
+    assert!(header.flags().production_version());
+    assert!(header.flags().debug_signed());
+}
@@ -0,0 +1,112 @@
+//! Check that Huffman-compressed data decompresses to the original data.
+
+mod common;
+
+use csme_rs::{ ext::*, huffman::* };
+use common::module_data;
+
+/// Return module attributes for Huffman-compressed data.
+fn module_attrs(uncompressed_size: usize, compressed_size: usize)
+    -> ModAttrExt
+{
+    common::module_attrs(CompressionType::Huff, uncompressed_size,
+        compressed_size, [0; 32])
+}
+
+#[test]
+fn roundtrip_compress_huff() {
+    let sizes = [(0x1000, 1), (0x9000, 2), (0x2f000, 3), (0x2e345, 5), (0x80, 6)];
+    for (len, seed) in sizes.iter() {
+        let data = module_data(*len, *seed);
+        let compressed = compress_huff(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        let attrs = module_attrs(data.len(), compressed.len());
+        assert_eq!(decompress_huff(&compressed, &attrs).unwrap(), data);
+    }
+}
+
+#[test]
+fn roundtrip_loaded_dictionary() {
+    let table = "# codeword symbol\n1 61\n01 62\n00 6363\n";
+    let dict = HuffmanDictionary::load(table).unwrap();
+    let dicts = HuffmanDictionaries { code: dict.clone(), data: dict };
+    let data: Vec<u8> = b"abccba".iter().copied().cycle().take(0x2000).collect();
+    let compressed = compress_huff_with(&data, &dicts).unwrap();
+    let attrs = module_attrs(data.len(), compressed.len());
+    assert_eq!(decompress_huff_with(&compressed, &attrs, &dicts).unwrap(), data);
+
+    assert!(HuffmanDictionary::load("1 61\n0x 62\n").is_err());
+    assert!(HuffmanDictionary::load("0 61\n1 62\n01 63\n").is_err());
+}
+
+#[test]
+fn select_dictionary_per_chunk() {
+    let code = HuffmanDictionary::load("1 61\n01 62\n00 6363\n").unwrap();
+    let data = HuffmanDictionary::load("1 78\n01 79\n00 7a7a\n").unwrap();
+    let dicts = HuffmanDictionaries { code, data };
+    let abc: Vec<u8> = b"abccba".iter().copied().cycle().take(CHUNK_LEN)
+        .collect();
+    let xyz: Vec<u8> = b"xyzzyx".iter().copied().cycle().take(CHUNK_LEN)
+        .collect();
+    let module = [&abc[..], &xyz, &abc].concat();
+
+    let compressed = compress_huff_with(&module, &dicts).unwrap();
+    let attrs = module_attrs(module.len(), compressed.len());
+    assert_eq!(decompress_huff_with(&compressed, &attrs, &dicts).unwrap(),
+        module);
+    // Only the second chunk uses the data dictionary
+    let flags: Vec<u8> = compressed[..12].chunks(4).map(|x| x[3] >> 1)
+        .collect();
+    assert_eq!(flags, [0, 1, 0]);
+
+    // Swap the dictionaries used for the first two chunks
+    let mut swapped = compressed;
+    swapped[3] |= 1 << 1;
+    swapped[7] &= !(1 << 1);
+    let page = decompress_huff_pages(&swapped, &attrs, &dicts, 0..2).unwrap();
+    assert_eq!(page, [&xyz[..], &abc].concat());
+}
+
+#[test]
+fn decompress_huff_page_ranges() {
+    let data = module_data(0x9000, 4);
+    let compressed = compress_huff(&data).unwrap();
+    let attrs = module_attrs(data.len(), compressed.len());
+    let dicts = HuffmanDictionaries::csme11();
+
+    let pages = decompress_huff_pages(&compressed, &attrs, dicts, 3..6).unwrap();
+    assert_eq!(pages, &data[3 * CHUNK_LEN..6 * CHUNK_LEN]);
+    let page = decompress_huff_page(&compressed, &attrs, dicts, 8).unwrap();
+    assert_eq!(page, &data[8 * CHUNK_LEN..]);
+    assert!(decompress_huff_page(&compressed, &attrs, dicts, 9).is_err());
+}
+
+#[test]
+fn decompress_huff_partial_page() {
+    let data = module_data(0x4321, 7);
+    let compressed = compress_huff(&data).unwrap();
+    let attrs = module_attrs(data.len(), compressed.len());
+    let dicts = HuffmanDictionaries::csme11();
+
+    let page = decompress_huff_page(&compressed, &attrs, dicts, 4).unwrap();
+    assert_eq!(page, &data[4 * CHUNK_LEN..]);
+    assert!(decompress_huff_page(&compressed, &attrs, dicts, 5).is_err());
+}
+
+#[test]
+fn decompress_huff_bad_streams() {
+    let data = module_data(0x3000, 8);
+    let compressed = compress_huff(&data).unwrap();
+    let attrs = module_attrs(data.len(), compressed.len());
+
+    // The last chunk starts past the end of the data
+    let mut bad = compressed.clone();
+    bad[8..12].copy_from_slice(&0x01ff_0000u32.to_le_bytes());
+    let err = decompress_huff(&bad, &attrs).unwrap_err();
+    assert_eq!((err.name(), err.offset()), ("HuffmanHeaderEntry", 8));
+
+    // The last chunk is truncated
+    let bad = &compressed[..compressed.len() - 0x10];
+    let err = decompress_huff(bad, &attrs).unwrap_err();
+    assert_eq!(err.name(), "HuffmanChunk");
+}
@@ -0,0 +1,156 @@
+//! Check that flash descriptors and ME images are located and decoded.
+
+use csme_rs::{ ifd::*, image::* };
+
+/// Build a flash image with a descriptor, an ME region at 0x1000-0x7fff
+/// and a BIOS region at 0x8000-0xffff.
+fn flash_image(version: DescriptorVersion) -> Vec<u8> {
+    let mut res = vec![0xff; 0x10000];
+    let mut put = |off: usize, x: u32| {
+        res[off..off + 4].copy_from_slice(&x.to_le_bytes());
+    };
+    put(0x10, 0x0ff0_a55a);
+    // FCBA 0x30, 2 components, FRBA 0x40, 4 regions
+    put(0x14, 0x0404_0103);
+    // FMBA 0x80, 2 masters, FPSBA 0x100, 2 PCH straps
+    put(0x18, 0x0210_0208);
+    // FMSBA 0xc0, 1 processor strap
+    put(0x1c, 0x0000_010c);
+
+    let (read_freq, masters) = match version {
+        // BIOS can read the descriptor, BIOS and GbE, and write BIOS and GbE
+        DescriptorVersion::V1 => (0, [0x0a0b_0000, 0x0c0d_0000, 0x0808_0000]),
+        DescriptorVersion::V2 => (6, [0x00a0_0b00, 0x00c0_0d00, 0x0080_0800]),
+    };
+    put(0x30, read_freq << 17);
+
+    let regions = [0, 0x000f_0008, 0x0007_0001];
+    for (i, x) in regions.iter().enumerate() {
+        put(0x40 + i * 4, *x);
+    }
+    for i in regions.len()..version.num_regions() {
+        put(0x40 + i * 4, 0x0000_7fff);
+    }
+    for (i, x) in masters.iter().enumerate() {
+        put(0x80 + i * 4, *x);
+    }
+    put(0x100, 0x1234_5678);
+    put(0x104, 0x9abc_def0);
+    res
+}
+
+#[test]
+fn decode_flash_map() {
+    let data = flash_image(DescriptorVersion::V2);
+    let ifd = FlashDescriptor::new(&data).unwrap();
+    let map = ifd.map;
+    assert_eq!((map.fcba(), map.num_components()), (0x30, 2));
+    assert_eq!((map.frba(), map.num_regions()), (0x40, 4));
+    assert_eq!((map.fmba(), map.num_masters()), (0x80, 2));
+    assert_eq!((map.fpsba(), map.pch_strap_len()), (0x100, 2));
+    assert_eq!((map.fmsba(), map.cpu_strap_len()), (0xc0, 1));
+    assert_eq!(ifd.pch_straps, [0x1234_5678, 0x9abc_def0]);
+
+    let mut data = data;
+    data[0x10] = 0;
+    assert!(FlashDescriptor::new(&data).is_err());
+}
+
+#[test]
+fn decode_flash_regions() {
+    for version in [DescriptorVersion::V1, DescriptorVersion::V2].iter() {
+        let data = flash_image(*version);
+        let ifd = FlashDescriptor::new(&data).unwrap();
+        assert_eq!(ifd.version, *version);
+        assert_eq!(ifd.regions.len(), version.num_regions());
+        assert_eq!(ifd.masters.len(), version.num_masters());
+
+        let me = ifd.me_region().unwrap();
+        assert_eq!((me.base(), me.limit(), me.len()), (0x1000, 0x7fff, 0x7000));
+        let bios = ifd.region(Region::Bios).unwrap();
+        assert_eq!((bios.base(), bios.len()), (0x8000, 0x8000));
+        // The descriptor region is a single 4KiB block
+        assert_eq!(ifd.region(Region::Descriptor).unwrap().len(), 0x1000);
+        assert!(ifd.region(Region::Gbe).is_none());
+        assert!(ifd.region(Region::Ec).is_none());
+
+        assert_eq!(ifd.region_data(&data, Region::Me).unwrap(),
+            &data[0x1000..0x8000]);
+        assert!(ifd.region_data(&data, Region::Gbe).is_err());
+        assert!(ifd.region_data(&data[..0xc000], Region::Bios).is_err());
+    }
+}
+
+#[test]
+fn decode_flash_masters() {
+    for version in [DescriptorVersion::V1, DescriptorVersion::V2].iter() {
+        let data = flash_image(*version);
+        let ifd = FlashDescriptor::new(&data).unwrap();
+        let bios = ifd.masters[0];
+        let readable: Vec<Region> = Region::ALL.iter().copied()
+            .filter(|r| bios.can_read(*version, *r)).collect();
+        assert_eq!(readable, [Region::Descriptor, Region::Bios, Region::Gbe]);
+        let writable: Vec<Region> = Region::ALL.iter().copied()
+            .filter(|r| bios.can_write(*version, *r)).collect();
+        assert_eq!(writable, [Region::Bios, Region::Gbe]);
+
+        let me = ifd.masters[1];
+        assert!(me.can_read(*version, Region::Me));
+        assert!(me.can_write(*version, Region::Me));
+        assert!(!me.can_write(*version, Region::Bios));
+    }
+}
+
+/// Build an FPT header (without entries).
+fn fpt_header() -> Vec<u8> {
+    let mut res = b"$FPT".to_vec();
+    res.extend_from_slice(&0u32.to_le_bytes());
+    res.extend_from_slice(&[0x20, 0x10, 0x20, 0]);
+    res.resize(0x20, 0);
+    res
+}
+
+#[test]
+fn locate_me_in_spi_flash() {
+    let mut data = flash_image(DescriptorVersion::V2);
+    let err = MeImage::new(&data).err().unwrap();
+    assert_eq!((err.name(), err.offset()), ("FptHeader", 0x1000));
+
+    data[0x1000..0x1020].copy_from_slice(&fpt_header());
+    let image = MeImage::new(&data).unwrap();
+    assert_eq!(image.kind, ImageKind::SpiFlash);
+    assert_eq!(image.me_offset, 0x1000);
+    assert_eq!(image.me_region, &data[0x1000..0x8000]);
+    assert_eq!(image.fpt_offset, Some(0));
+    assert!(image.fpt().unwrap().is_ok());
+}
+
+#[test]
+fn locate_me_in_me_region() {
+    for rom_bypass in [false, true].iter() {
+        let mut data = if *rom_bypass { vec![0; 0x10] } else { Vec::new() };
+        data.extend_from_slice(&fpt_header());
+        data.resize(0x1000, 0xff);
+
+        let image = MeImage::new(&data).unwrap();
+        assert_eq!(image.kind, ImageKind::MeRegion { rom_bypass: *rom_bypass });
+        assert_eq!((image.me_offset, image.me_region.len()), (0, 0x1000));
+        let fpt_offset = if *rom_bypass { 0x10 } else { 0 };
+        assert_eq!(image.fpt_offset, Some(fpt_offset));
+        assert!(image.fpt().unwrap().is_ok());
+    }
+}
+
+#[test]
+fn locate_me_in_update_image() {
+    let mut data = b"$CPD".to_vec();
+    data.resize(0x100, 0);
+    let image = MeImage::new(&data).unwrap();
+    assert_eq!(image.kind, ImageKind::Update);
+    assert_eq!(image.fpt_offset, None);
+    assert!(image.fpt().is_none());
+
+    data[0] = b'#';
+    let err = MeImage::new(&data).err().unwrap();
+    assert_eq!(err.name(), "MeImage");
+}
@@ -0,0 +1,74 @@
+//! Check classifying the keys used to sign manifests.
+
+use csme_rs::{ keys::*, man::* };
+
+const MAN_2048: &[u8] = include_bytes!("data/man2048.bin");
+const MAN_3072: &[u8] = include_bytes!("data/man3072.bin");
+
+/// Hashes of the public keys in the signed manifests.
+const KEY_2048: &str =
+    "651e205aa9cdcbbc6aafb14754e1f9b0589831a0f343d29778f14070eda7d976";
+const KEY_3072: &str =
+    "8ac24e3a09bc30032650486960764c144b691ec36bf5d3c8e7e484074d27f210";
+
+fn hash(x: &str) -> [u8; 32] {
+    let mut res = [0; 32];
+    hex::decode_to_slice(x, &mut res).unwrap();
+    res
+}
+
+#[test]
+fn public_key_hashes() {
+    let manifests = [(MAN_2048, KEY_2048), (MAN_3072, KEY_3072)];
+    for (data, expected) in manifests.iter() {
+        let man = CodePartitionManifest::new(data).unwrap();
+        assert_eq!(man.crypto.public_key_hash(), hash(expected));
+    }
+}
+
+#[test]
+fn load_key_database() {
+    let text = format!("# <SHA-256 hash> <class> [description]\n\
+        {}  production     Some production key\n\
+        \n\
+        {}\tdebug # Some debug key\n", KEY_2048, KEY_3072);
+    let mut keys = KeyDatabase::new();
+    keys.load(&text).unwrap();
+
+    let info = keys.lookup(&hash(KEY_2048)).unwrap();
+    assert_eq!(info.class, KeyClass::IntelProduction);
+    assert_eq!(info.description, "Some production key");
+    let info = keys.lookup(&hash(KEY_3072)).unwrap();
+    assert_eq!(info.class, KeyClass::IntelPreProduction);
+    assert_eq!(info.description, "");
+
+    // Loading more keys replaces existing entries
+    keys.load(&format!("{} oem\n", KEY_3072)).unwrap();
+    assert_eq!(keys.classify(&hash(KEY_3072)), KeyClass::Oem);
+}
+
+#[test]
+fn reject_bad_key_lines() {
+    let lines = [
+        format!("{} production\n{}\n", KEY_2048, KEY_3072),
+        format!("{} production\n{} intel\n", KEY_2048, KEY_3072),
+        format!("{} production\n{} oem\n", KEY_2048, &KEY_3072[2..]),
+    ];
+    for text in lines.iter() {
+        let err = KeyDatabase::new().load(text).unwrap_err();
+        assert_eq!((err.name(), err.offset()), ("KeyDatabase", 76));
+    }
+}
+
+#[test]
+fn classify_keys() {
+    let mut keys = KeyDatabase::new();
+    keys.insert(hash(KEY_2048), KeyClass::Oem, "Some OEM key");
+    for (data, class) in [(MAN_2048, KeyClass::Oem),
+        (MAN_3072, KeyClass::Unknown)].iter()
+    {
+        let man = CodePartitionManifest::new(data).unwrap();
+        assert_eq!(keys.classify(&man.crypto.public_key_hash()), *class);
+    }
+    assert!(keys.lookup(&hash(KEY_3072)).is_none());
+}
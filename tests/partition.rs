@@ -0,0 +1,199 @@
+//! Check code partitions against the partition info in their manifests.
+//!
+//! `tests/data/ftpr.bin` is a small CSME 11 style partition (with a version
+//! 1 directory): a manifest signed with the same throwaway key as
+//! `man2048.bin`, and two uncompressed modules (`bup` and `kernel`) with
+//! their metadata files. All digests are stored in reversed byte order.
+
+mod common;
+
+use csme_rs::{ cpd::*, ext::*, part::*, rsa::SignatureStatus };
+
+const FTPR: &[u8] = include_bytes!("data/ftpr.bin");
+
+/// Offset of the manifest in the fixture.
+const MAN_OFF: usize = 0x100;
+/// Offset of the end of the manifest in the fixture.
+const MAN_END: usize = 0x444;
+
+/// Digest over the fixture without the manifest (as stored).
+const FTPR_DIGEST: &str =
+    "8c3f1a46e6e89b0ad98d45434e5156c3e262e722cd5dda4117836b7bece81d84";
+
+#[test]
+fn verify_partition_info() {
+    let part = CodePartition::new(FTPR).unwrap();
+    assert_eq!(part.man.verify_signature(), SignatureStatus::Valid);
+    assert_eq!(part.digest_order(), Some(DigestOrder::Reversed));
+    let info = part.partition_info().unwrap();
+    assert_eq!(&info.part_name(), b"FTPR");
+    assert_eq!(info.part_len(), FTPR.len());
+    assert_eq!(hex::encode(info.part_digest()), FTPR_DIGEST);
+    assert_eq!(part.verify_partition_info(Some(*b"FTPR")).unwrap(), vec![]);
+}
+
+#[test]
+fn partition_digest_skips_manifest() {
+    // Changing the manifest (outside of the extension) doesn't matter
+    let mut data = FTPR.to_vec();
+    data[MAN_OFF + 0x100] ^= 1;
+    let part = CodePartition::new(&data).unwrap();
+    assert_eq!(part.verify_partition_info(None).unwrap(), vec![]);
+
+    // Changing the data before or after the manifest does
+    for off in [0xa0, MAN_END, FTPR.len() - 1].iter() {
+        let mut data = FTPR.to_vec();
+        data[*off] ^= 1;
+        let part = CodePartition::new(&data).unwrap();
+        let res = part.verify_partition_info(None).unwrap();
+        assert!(matches!(res[..], [PartitionInfoMismatch::Digest { .. }]),
+            "offset {:#x}", off);
+    }
+}
+
+#[test]
+fn digest_order_by_generation() {
+    let part = CodePartition::new(FTPR).unwrap();
+    assert_eq!(DigestOrder::for_cpd(&part.cpd), Some(DigestOrder::Reversed));
+
+    // Version 2 directories are used by CSME 12 and later
+    let mut data = b"$CPD".to_vec();
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&[2, 1, 0x14, 0]);
+    data.extend_from_slice(b"FTPR");
+    data.extend_from_slice(&0u32.to_le_bytes());
+    let cpd = CodePartitionDirectory::new(&data).unwrap();
+    assert_eq!(DigestOrder::for_cpd(&cpd), None);
+
+    let mut digest = [0; 32];
+    digest.iter_mut().enumerate().for_each(|(i, x)| *x = i as u8);
+    assert_eq!(DigestOrder::Normal.apply(digest), digest);
+    let reversed = DigestOrder::Reversed.apply(digest);
+    assert!(reversed.iter().eq(digest.iter().rev()));
+}
+
+#[test]
+fn partition_info_names() {
+    let part = CodePartition::new(FTPR).unwrap();
+    assert_eq!(part.verify_partition_info(Some(*b"NFTP")).unwrap(),
+        vec![PartitionInfoMismatch::FptName {
+            expected: *b"FTPR", actual: *b"NFTP",
+        }]);
+}
+
+/// Offset of the module list (in the `PartitionInfo` extension).
+const MODULE_INFO: usize = 0x3dc;
+/// Offset of the metadata file for `bup`.
+const BUP_MET: usize = 0x480;
+
+#[test]
+fn verify_module_metadata() {
+    let part = CodePartition::new(FTPR).unwrap();
+    let names: Vec<String> = part.module_info().iter().map(|m| m.name())
+        .collect();
+    assert_eq!(names, ["bup", "kernel"]);
+    assert_eq!(part.verify_metadata(), vec![]);
+}
+
+#[test]
+fn metadata_size_mismatch() {
+    let mut data = FTPR.to_vec();
+    data[MODULE_INFO + 16] = 0x40;
+    let part = CodePartition::new(&data).unwrap();
+    assert_eq!(part.verify_metadata(), vec![MetadataMismatch::Size {
+        name: "bup".to_owned(), expected: 0x40, actual: 0x38,
+    }]);
+}
+
+#[test]
+fn metadata_digest_mismatch() {
+    let mut data = FTPR.to_vec();
+    // A reserved byte in the module attributes
+    data[BUP_MET + 9] ^= 1;
+    let part = CodePartition::new(&data).unwrap();
+    let res = part.verify_metadata();
+    assert!(matches!(&res[..], [MetadataMismatch::Digest { name, .. }]
+        if name == "bup"), "{:?}", res);
+
+    // The digest in the manifest is reversed
+    let mut data = FTPR.to_vec();
+    data[MODULE_INFO + 20..MODULE_INFO + 52].reverse();
+    let part = CodePartition::new(&data).unwrap();
+    assert_eq!(part.verify_metadata().len(), 1);
+}
+
+#[test]
+fn metadata_missing_and_extra() {
+    let mut data = FTPR.to_vec();
+    data[MODULE_INFO + 2] = b'x';
+    let part = CodePartition::new(&data).unwrap();
+    assert_eq!(part.verify_metadata(), vec![
+        MetadataMismatch::Missing { name: "bux".to_owned() },
+        MetadataMismatch::Extra { name: "bup".to_owned() },
+    ]);
+}
+
+/// SHA-256 digest of `abc`, in the order stored in module attributes.
+const ABC_DIGEST: &str =
+    "ad1500f261ff10b49c7a1796a36103b02322ae5dde404141eacf018fbf1678ba";
+
+/// Build a module with the digest of `abc` in its attributes, where only
+/// the data covered by the digest is `abc`.
+fn abc_module(compression_type: CompressionType) -> Module {
+    let mut sha256_digest = [0; 32];
+    hex::decode_to_slice(ABC_DIGEST, &mut sha256_digest).unwrap();
+    let (data, raw_data) = match compression_type {
+        CompressionType::Lzma => (b"abcabc".to_vec(), b"abc".to_vec()),
+        _ => (b"abc".to_vec(), b"cba".to_vec()),
+    };
+    Module {
+        name: "abc".to_owned(),
+        attr: common::module_attrs(compression_type, 3, raw_data.len(),
+            sha256_digest),
+        ext: Vec::new(),
+        data, raw_data,
+        digest_order: Some(DigestOrder::Reversed),
+    }
+}
+
+#[test]
+fn verify_module_digests() {
+    let types = [CompressionType::None, CompressionType::Lzma,
+        CompressionType::Huff];
+    for kind in types.iter() {
+        let mut module = abc_module(*kind);
+        assert_eq!(module.verify_digest().unwrap(), DigestStatus::Valid,
+            "{:?}", kind);
+
+        // The digest isn't accepted in the other byte order
+        module.attr.sha256_digest.reverse();
+        let expected = module.attr.sha256_digest;
+        let mut computed = expected;
+        computed.reverse();
+        assert_eq!(module.verify_digest().unwrap(),
+            DigestStatus::Invalid { expected, computed }, "{:?}", kind);
+    }
+}
+
+#[test]
+fn verify_partition_module_digests() {
+    let part = CodePartition::new(FTPR).unwrap();
+    assert_eq!(part.modules.len(), 2);
+    for module in part.modules.values() {
+        assert_eq!(module.digest_order, Some(DigestOrder::Reversed));
+        assert_eq!(module.verify_digest().unwrap(), DigestStatus::Valid);
+    }
+}
+
+#[test]
+fn unknown_digest_order() {
+    // The module was fine, but we don't know how to check it
+    let mut module = abc_module(CompressionType::None);
+    module.digest_order = None;
+    assert_eq!(module.verify_digest().unwrap(), DigestStatus::Unsupported);
+
+    let mut module = abc_module(CompressionType::None);
+    module.digest_order = Some(DigestOrder::Normal);
+    module.attr.sha256_digest.reverse();
+    assert_eq!(module.verify_digest().unwrap(), DigestStatus::Valid);
+}
@@ -0,0 +1,249 @@
+//! Check that decoding and then encoding a structure reproduces its bytes.
+
+use csme_rs::{ FromBytes, ToBytes, bytes::Field };
+use csme_rs::{ fpt::*, cpd::*, man::*, ext::* };
+
+/// Return some arbitrary (but printable) bytes.
+fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (0x20 + (i * 37 + seed * 11) % 0x5f) as u8).collect()
+}
+
+fn roundtrip<T: FromBytes>(bytes: &[u8]) {
+    let value = T::from_bytes(bytes).unwrap();
+    assert_eq!(value.to_bytes(), bytes);
+}
+
+fn fpt_header(num_entries: u32) -> Vec<u8> {
+    let mut res = pattern(FptHeader::SIZE, 1);
+    res[0x00..0x04].copy_from_slice(b"$FPT");
+    res[0x04..0x08].copy_from_slice(&num_entries.to_le_bytes());
+    res[0x0a] = 0x20;
+    res
+}
+
+fn cpd_header(num_entries: u32, version: u8) -> Vec<u8> {
+    let mut res = pattern(CpdHeader::SIZE, 2);
+    res[0x00..0x04].copy_from_slice(b"$CPD");
+    res[0x04..0x08].copy_from_slice(&num_entries.to_le_bytes());
+    res[0x08] = version;
+    res[0x0a] = if version == 2 { 0x14 } else { 0x10 };
+    if version == 2 {
+        res.extend_from_slice(&pattern(4, 12));
+    }
+    res
+}
+
+/// Build a manifest header for some RSA key size (in bytes).
+fn manifest_header(key_len: u32) -> Vec<u8> {
+    let header_len = ManifestHeader::SIZE as u32 + key_len * 2 + 4;
+    let mut res = pattern(ManifestHeader::SIZE, 3);
+    res[0x04..0x08].copy_from_slice(&(header_len / 4).to_le_bytes());
+    res[0x10..0x14].copy_from_slice(&0x8086u32.to_le_bytes());
+    res[0x1c..0x20].copy_from_slice(b"$MN2");
+    res[0x78..0x7c].copy_from_slice(&(key_len / 4).to_le_bytes());
+    res[0x7c..0x80].copy_from_slice(&1u32.to_le_bytes());
+    res
+}
+
+/// Build an extension with some fixed-size data followed by entries.
+fn extension(id: u32, data_len: usize, entry_len: usize) -> Vec<u8> {
+    let body_len = data_len + 3 * entry_len;
+    let mut res = Vec::new();
+    res.extend_from_slice(&id.to_le_bytes());
+    res.extend_from_slice(&(8 + body_len as u32).to_le_bytes());
+    res.extend_from_slice(&pattern(body_len, id as usize));
+    match id {
+        // Compression type must be valid
+        0xa => res[8] = 1,
+        // Hashes must be SHA-256 digests
+        0xe => set_hash_sizes(&mut res[8 + data_len..], entry_len, 34, 32),
+        0xf => set_hash_sizes(&mut res[8 + data_len..], entry_len, 14, 32),
+        0x16 => res[8 + 25..8 + 28].copy_from_slice(&[32, 0, 0]),
+        _ => {},
+    }
+    res
+}
+
+/// Set the 16-bit hash size field at `offset` in each entry.
+fn set_hash_sizes(entries: &mut [u8], entry_len: usize, offset: usize,
+    hash_size: u16)
+{
+    for entry in entries.chunks_exact_mut(entry_len) {
+        entry[offset..offset + 2].copy_from_slice(&hash_size.to_le_bytes());
+    }
+}
+
+#[test]
+fn roundtrip_fpt_structs() {
+    roundtrip::<FptHeader>(&fpt_header(2));
+    roundtrip::<FptEntry>(&pattern(FptEntry::SIZE, 4));
+}
+
+#[test]
+fn roundtrip_cpd_structs() {
+    roundtrip::<CpdHeader>(&cpd_header(2, 1));
+    roundtrip::<CpdEntry>(&pattern(CpdEntry::SIZE, 5));
+}
+
+#[test]
+fn roundtrip_manifest_structs() {
+    roundtrip::<ManifestHeader>(&manifest_header(256));
+    roundtrip::<ManifestHeader>(&manifest_header(384));
+    roundtrip::<ExtensionHeader>(&pattern(ExtensionHeader::SIZE, 7));
+}
+
+#[test]
+fn roundtrip_extension_structs() {
+    macro_rules! check {
+        ($($ty:ty),* $(,)?) => { $(
+            roundtrip::<$ty>(&pattern(<$ty>::SIZE, 8));
+        )* }
+    }
+    check!(
+        SystemInfoExt, InitScriptExt, FeaturePermissionsExt,
+        ManifestPartitionInfoExt, SharedLibExt, ManProcessExt,
+        SpecialFileProducerExt, ClientSystemInfoExt, KeyManifestExt,
+        SignedPackageInfoExt, IfwiPartitionInfoExt,
+        IndependentPartitionEntry, InitScriptEntry, FeaturePermissionsEntry,
+        ManifestModuleInfoExt, ProcessGroupId, Thread, LockedRange, Device,
+        MmioRange, SpecialFileDef, UserInfoEntry, KeyManifestEntry,
+    );
+    let mut attrs = pattern(ModAttrExt::SIZE, 8);
+    attrs[0] = 2;
+    roundtrip::<ModAttrExt>(&attrs);
+}
+
+#[test]
+fn roundtrip_flash_partition_table() {
+    let mut data = fpt_header(2);
+    data.extend_from_slice(&pattern(2 * FptEntry::SIZE, 9));
+    let fpt = FlashPartitionTable::from_bytes(&data).unwrap();
+    assert_eq!(fpt.entries.len(), 2);
+    assert_eq!(fpt.to_bytes(), data);
+}
+
+#[test]
+fn roundtrip_code_partition_directory() {
+    for version in [1, 2].iter() {
+        let mut data = cpd_header(2, *version);
+        data.extend_from_slice(&pattern(2 * CpdEntry::SIZE, 10));
+        let cpd = CodePartitionDirectory::new(&data).unwrap();
+        assert_eq!(cpd.entries.len(), 2);
+        assert_eq!(cpd.to_bytes(), data);
+    }
+}
+
+#[test]
+fn cpd_entry_names() {
+    let mut entry = pattern(CpdEntry::SIZE, 13);
+    // Padding after the name doesn't need to be UTF-8
+    entry[..12].copy_from_slice(b"FTPR.man\0\xff\xfe\0");
+    let e = CpdEntry::from_bytes(&entry).unwrap();
+    assert_eq!(e.filename(), "FTPR.man");
+
+    entry[..12].copy_from_slice(b"FTPR\xffman\0\0\0\0");
+    assert!(CpdEntry::from_bytes(&entry).is_err());
+    // Entries built by hand can still have bad names
+    let e = CpdEntry { name: *b"FTPR\xffman\0\0\0\0", ..Default::default() };
+    assert_eq!(e.filename(), "FTPR");
+}
+
+#[test]
+fn manifest_extension_sizes() {
+    assert_eq!(KeyManifestExt::SIZE, 28);
+    assert_eq!(KeyManifestEntry::SIZE, 68);
+    assert_eq!(SignedPackageInfoExt::SIZE, 44);
+    assert_eq!(ManifestModuleInfoExt::SIZE, 52);
+    assert_eq!(IfwiPartitionInfoExt::SIZE, 80);
+}
+
+#[test]
+fn roundtrip_manifest_extensions() {
+    let layouts = [
+        (0x0, SystemInfoExt::SIZE, IndependentPartitionEntry::SIZE),
+        (0x1, InitScriptExt::SIZE, InitScriptEntry::SIZE),
+        (0x2, FeaturePermissionsExt::SIZE, FeaturePermissionsEntry::SIZE),
+        (0x3, ManifestPartitionInfoExt::SIZE, ManifestModuleInfoExt::SIZE),
+        (0x4, SharedLibExt::SIZE, 0),
+        (0x5, ManProcessExt::SIZE, ProcessGroupId::SIZE),
+        (0x6, 0, Thread::SIZE),
+        (0x7, 0, Device::SIZE),
+        (0x8, 0, MmioRange::SIZE),
+        (0x9, SpecialFileProducerExt::SIZE, SpecialFileDef::SIZE),
+        (0xa, ModAttrExt::SIZE, 0),
+        (0xb, 0, LockedRange::SIZE),
+        (0xc, ClientSystemInfoExt::SIZE, 0),
+        (0xd, 0, UserInfoEntry::SIZE),
+        (0xe, KeyManifestExt::SIZE, KeyManifestEntry::SIZE),
+        (0xf, SignedPackageInfoExt::SIZE, ManifestModuleInfoExt::SIZE),
+        (0x16, IfwiPartitionInfoExt::SIZE, 0),
+        // Unknown extensions are kept as raw bytes
+        (0x7f, 0x1d, 0),
+    ];
+    for (id, data_len, entry_len) in layouts.iter() {
+        let data = extension(*id, *data_len, *entry_len);
+        let ext = ManifestExtension::new(&data).unwrap();
+        assert_eq!(ext.to_bytes(), data, "extension {:#x}", id);
+    }
+}
+
+#[test]
+fn sha384_extensions_are_unknown() {
+    let is_unknown = |data: &[u8]| {
+        let ext = ManifestExtension::new(data).unwrap();
+        assert_eq!(ext.to_bytes(), data);
+        matches!(ext.data, ExtensionData::Unknown { .. })
+    };
+
+    // Key manifest entries with 48-byte hashes
+    let entry_len = KeyManifestEntry::SIZE + 16;
+    let mut data = extension(0xe, KeyManifestExt::SIZE, entry_len);
+    set_hash_sizes(&mut data[8 + KeyManifestExt::SIZE..], entry_len, 34, 48);
+    assert!(is_unknown(&data));
+    // Entries of the right length, but with the wrong hash size
+    let mut data = extension(0xe, KeyManifestExt::SIZE, KeyManifestEntry::SIZE);
+    assert!(!is_unknown(&data));
+    data[8 + KeyManifestExt::SIZE + 34] = 48;
+    assert!(is_unknown(&data));
+
+    let entry_len = ManifestModuleInfoExt::SIZE + 16;
+    let mut data = extension(0xf, SignedPackageInfoExt::SIZE, entry_len);
+    set_hash_sizes(&mut data[8 + SignedPackageInfoExt::SIZE..], entry_len,
+        14, 48);
+    assert!(is_unknown(&data));
+
+    let mut data = extension(0x16, IfwiPartitionInfoExt::SIZE + 16, 0);
+    data[8 + 25] = 48;
+    assert!(is_unknown(&data));
+}
+
+#[test]
+fn reject_bad_sha256_extensions() {
+    let layouts = [
+        (0xe, KeyManifestExt::SIZE, KeyManifestEntry::SIZE),
+        (0xf, SignedPackageInfoExt::SIZE, ManifestModuleInfoExt::SIZE),
+        (0x16, IfwiPartitionInfoExt::SIZE, 0),
+    ];
+    for (id, data_len, entry_len) in layouts.iter() {
+        // Extensions with SHA-256 hashes, but a truncated entry or data
+        let mut data = extension(*id, *data_len, *entry_len);
+        data.truncate(data.len() - 4);
+        let len = data.len() as u32;
+        data[4..8].copy_from_slice(&len.to_le_bytes());
+        assert!(ManifestExtension::new(&data).is_err(), "extension {:#x}", id);
+    }
+}
+
+#[test]
+fn roundtrip_code_partition_manifest() {
+    for key_len in [256, 384].iter() {
+        let mut data = manifest_header(*key_len);
+        data.extend_from_slice(&pattern(*key_len as usize * 2 + 4, 11));
+        data.extend_from_slice(&extension(0x6, 0, Thread::SIZE));
+        data.extend_from_slice(&extension(0xa, ModAttrExt::SIZE, 0));
+        let man = CodePartitionManifest::new(&data).unwrap();
+        assert_eq!(man.crypto.public_key.len(), *key_len as usize);
+        assert_eq!(man.extensions.len(), 2);
+        assert_eq!(man.to_bytes(), data);
+    }
+}
@@ -0,0 +1,60 @@
+//! Check RSA signatures on manifests.
+//!
+//! The manifests in `tests/data` are signed with throwaway keys: each has a
+//! header, a crypto block and a single extension.
+
+use csme_rs::{ FromBytes, bytes::Field, man::*, rsa::* };
+
+const MAN_2048: &[u8] = include_bytes!("data/man2048.bin");
+const MAN_3072: &[u8] = include_bytes!("data/man3072.bin");
+
+#[test]
+fn verify_manifest_signatures() {
+    for (data, key_len) in [(MAN_2048, 256), (MAN_3072, 384)].iter() {
+        let man = CodePartitionManifest::new(data).unwrap();
+        assert_eq!(man.crypto.public_key.len(), *key_len);
+        assert_eq!(man.verify_signature(), SignatureStatus::Valid);
+    }
+}
+
+#[test]
+fn signed_data_skips_crypto_block() {
+    for data in [MAN_2048, MAN_3072].iter() {
+        let man = CodePartitionManifest::new(data).unwrap();
+        let body_off = ManifestHeader::SIZE + man.crypto.len();
+        let mut expected = data[..ManifestHeader::SIZE].to_vec();
+        expected.extend_from_slice(&data[body_off..]);
+        assert_eq!(man.signed_data(), expected);
+    }
+}
+
+#[test]
+fn reject_tampered_manifests() {
+    for data in [MAN_2048, MAN_3072].iter() {
+        let man = CodePartitionManifest::new(data).unwrap();
+        // Somewhere in the header, the signature, and the extension
+        let offsets = [0x24, ManifestHeader::SIZE + man.crypto.len() - 1,
+            data.len() - 1];
+        for off in offsets.iter() {
+            let mut data = data.to_vec();
+            data[*off] ^= 1;
+            let man = CodePartitionManifest::new(&data).unwrap();
+            assert_eq!(man.verify_signature(), SignatureStatus::Invalid,
+                "offset {:#x}", off);
+        }
+    }
+}
+
+#[test]
+fn reject_unsupported_key_sizes() {
+    let man = CodePartitionManifest::new(MAN_2048).unwrap();
+    let status = verify_pkcs1_sha256(&man.crypto.public_key[..128],
+        man.crypto.exponent, &man.crypto.rsa_signature[..128],
+        &man.signed_data());
+    assert_eq!(status, SignatureStatus::UnsupportedKeySize(1024));
+
+    // The header says how large the key is
+    let mut data = MAN_2048.to_vec();
+    data[0x78..0x7c].copy_from_slice(&0x20u32.to_le_bytes());
+    assert!(ManifestHeader::from_bytes(&data).is_err());
+}